@@ -0,0 +1,113 @@
+//! Compares the throughput of building a `reqwest::Client` per request (the former
+//! `ApiImpl` behavior) with the pooled client of `ApiImpl`, with and without idle connections
+//! kept by `--api-pool-max-idle-per-host`.
+//!
+//! The API is a local hyper server which returns an empty 200 response.
+//!
+//! ```bash
+//! $ cargo bench --bench api_client
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use sqsproxyd::domain::message::Message;
+use sqsproxyd::infra::api::{new_api, Api};
+use sqsproxyd::Config;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use structopt::StructOpt;
+use tokio::runtime::Runtime;
+
+const NUM_REQUESTS: u64 = 100;
+
+fn start_server(runtime: &Runtime) -> SocketAddr {
+    let _guard = runtime.enter();
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let addr = server.local_addr();
+    runtime.spawn(server);
+    addr
+}
+
+fn api(url: &str, args: &[&str]) -> Arc<dyn Api + Send + Sync> {
+    let config = Config::from_iter(
+        [
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-url",
+            url,
+        ]
+        .iter()
+        .chain(args),
+    );
+    new_api(config).unwrap()
+}
+
+fn message() -> Message {
+    Message {
+        body: "{\"key1\": 1}".to_string(),
+        receipt_handle: "receipt_handle".to_string(),
+        md5_of_body: "dummy".to_string(),
+        message_id: "message_id".to_string(),
+        attributes: HashMap::new(),
+        receive_count: None,
+        sent_timestamp: None,
+        trace_header: None,
+    }
+}
+
+fn bench_api_client(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let url = format!("http://{}/", start_server(&runtime));
+    let message = message();
+
+    let mut group = c.benchmark_group("api_client");
+    group.throughput(Throughput::Elements(NUM_REQUESTS));
+
+    group.bench_function(BenchmarkId::new("client_per_request", NUM_REQUESTS), |b| {
+        b.to_async(&runtime).iter(|| async {
+            for _ in 0..NUM_REQUESTS {
+                reqwest::Client::new()
+                    .post(&url)
+                    .body(message.body.clone())
+                    .send()
+                    .await
+                    .unwrap()
+                    .text()
+                    .await
+                    .unwrap();
+            }
+        })
+    });
+
+    for (name, args) in [
+        ("api_impl", &[][..]),
+        (
+            "api_impl_without_idle",
+            &["--api-pool-max-idle-per-host", "0"],
+        ),
+    ] {
+        let api = api(&url, args);
+        group.bench_function(BenchmarkId::new(name, NUM_REQUESTS), |b| {
+            b.to_async(&runtime).iter(|| async {
+                for _ in 0..NUM_REQUESTS {
+                    let (is_succeeded, _) = api.post(&message).await.unwrap();
+                    assert!(is_succeeded);
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_api_client);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+//! Verifies a request signed with `--api-signing-key-file`, as the API side would.
+//! It shares the signing code with sqsproxyd, so API teams can use it as the reference
+//! implementation.
+//!
+//! ```bash
+//! $ echo -n "$BODY" | cargo run --example verify_signature -- \
+//!     /run/secrets/signing-key "$TIMESTAMP" "$MESSAGE_ID" "$SIGNATURE"
+//! ```
+
+#[path = "../src/infra/signature.rs"]
+#[allow(dead_code)]
+mod signature;
+
+use std::io::Read;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Maximum difference between the signed timestamp and now.
+const TOLERANCE: Duration = Duration::from_secs(300);
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 5 {
+        eprintln!(
+            "Usage: {} <key file> <timestamp> <message id> <signature> < body",
+            args[0]
+        );
+        process::exit(2);
+    }
+    let key = std::fs::read_to_string(&args[1]).expect("Failed to read the key file.");
+    let timestamp = args[2].parse().expect("Timestamp should be Unix seconds.");
+    let mut body = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut body)
+        .expect("Failed to read the body.");
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    if signature::verify(
+        key.trim_end_matches(&['\r', '\n'][..]).as_bytes(),
+        timestamp,
+        &args[3],
+        &body,
+        &args[4],
+        now,
+        TOLERANCE,
+    ) {
+        println!("valid");
+    } else {
+        println!("invalid");
+        process::exit(1);
+    }
+}
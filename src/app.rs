@@ -1 +1,6 @@
+pub mod admin;
 pub mod daemon;
+pub mod health;
+pub mod limiter;
+pub mod reloadable;
+pub mod state;
@@ -0,0 +1,86 @@
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::app::health::Health;
+use crate::app::state::State;
+use crate::infra::metrics;
+
+/// Serves the admin HTTP endpoints.
+///
+/// - `GET /metrics`: metrics in the Prometheus text format
+/// - `GET /healthz`: 200 if the process is alive and the receive loop ticked recently
+/// - `GET /readyz`: 200 if the SQS queue and the API are reachable and the daemon is not draining
+/// - `POST /pause`: stops receiving new messages, while in-flight messages are still processed
+/// - `POST /resume`: restarts receiving messages
+pub async fn serve(addr: SocketAddr, health: Health, state: Arc<State>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let health = health.clone();
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let health = health.clone();
+                let state = state.clone();
+                async move { handle(req, health, state).await }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!("Admin server is listening on {}.", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+async fn handle(
+    req: Request<Body>,
+    health: Health,
+    state: Arc<State>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => match metrics::encode() {
+            Ok((content_type, body)) => Response::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body)),
+            Err(e) => {
+                error!("Failed to encode metrics. ({:?})", e);
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+            }
+        },
+        (&Method::GET, "/healthz") => probe_response(health.live()),
+        (&Method::GET, "/readyz") => probe_response(health.ready().await),
+        (&Method::POST, "/pause") => {
+            if state.pause() {
+                info!("Pause requested via admin API.");
+            }
+            Response::builder().body(Body::from("paused"))
+        }
+        (&Method::POST, "/resume") => {
+            if state.resume() {
+                info!("Resume requested via admin API.");
+            }
+            Response::builder().body(Body::from("resumed"))
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+fn probe_response(result: Result<()>) -> http::Result<Response<Body>> {
+    match result {
+        Ok(()) => Response::builder().body(Body::from("ok")),
+        Err(e) => {
+            warn!("Probe failed. ({})", e);
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from(e.to_string()))
+        }
+    }
+}
@@ -1,94 +1,294 @@
-use crate::{ApiImpl, AwsSqs};
+use crate::AwsSqs;
 use anyhow::{anyhow, Result};
 use std::borrow::Borrow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio::{
-    sync::{broadcast, mpsc},
-    time::{sleep, Duration},
+    sync::{broadcast, mpsc, oneshot},
+    time::{sleep, timeout, Duration},
 };
-use tracing::{debug, error, warn};
+use tracing::{debug, error, error_span, field, info, warn, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use url::Url;
 
-use crate::domain::config::Config;
-use crate::domain::message::Message;
-use crate::infra::api::Api;
-use crate::infra::logging::panic;
+use crate::app::health::Health;
+use crate::app::limiter::{LimitedApi, Limiter};
+use crate::app::reloadable::ReloadableApi;
+use crate::app::state::State;
+use crate::domain::config::{ConcurrencyMode, Config, InvalidMessagePolicy};
+use crate::domain::error::{ApiError, FatalError, SqsError, TransformError};
+use crate::domain::message::{Message, OutputMessage};
+use crate::domain::transform::ResponseTransform;
+use crate::infra::api::{new_api, Api};
+use crate::infra::metrics::Metrics;
 use crate::infra::sqs::Sqs;
+use crate::infra::telemetry;
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_THROTTLE_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_THROTTLE_BACKOFF: Duration = Duration::from_secs(20);
 
 pub struct Daemon {
     config: Config,
-    sqs: Box<dyn Sqs + Send + Sync>,
-    api: Box<dyn Api + Send + Sync>,
+    sqs: Arc<dyn Sqs + Send + Sync>,
+    output_sqs: Option<Arc<dyn Sqs + Send + Sync>>,
+    api: Arc<ReloadableApi>,
+    metrics: Metrics,
+    state: Arc<State>,
+}
+
+/// Dependencies owned by each worker task.
+#[derive(Clone)]
+struct Worker {
+    id: usize,
+    queue: String,
+    sqs: Arc<dyn Sqs + Send + Sync>,
+    api: Arc<dyn Api + Send + Sync>,
+    output_sqs: Option<Arc<dyn Sqs + Send + Sync>>,
+    metrics: Metrics,
+    max_receive_count: Option<u32>,
+    invalid_message_policy: InvalidMessagePolicy,
+    response_transform: ResponseTransform,
+    /// Set in adaptive mode.
+    limiter: Option<Arc<Limiter>>,
+}
+
+/// Worker tasks, which can be resized at runtime.
+struct WorkerPool {
+    worker: Worker,
+    rx: async_channel::Receiver<Message>,
+    waiting_tx: mpsc::UnboundedSender<()>,
+    /// Waiting tokens sent by the workers which have been stopped while idle.
+    revoked_tokens: Arc<AtomicUsize>,
+    shutdown_tx: broadcast::Sender<()>,
+    heartbeat_tx: Option<mpsc::Sender<()>>,
+    stop_txs: Vec<oneshot::Sender<()>>,
+    next_id: usize,
+}
+
+impl WorkerPool {
+    fn resize(&mut self, size: usize) {
+        while self.stop_txs.len() < size {
+            let (stop_tx, stop_rx) = oneshot::channel();
+            let worker = Worker {
+                id: self.next_id,
+                ..self.worker.clone()
+            };
+            self.next_id += 1;
+            let rx = self.rx.clone();
+            let waiting_tx = self.waiting_tx.clone();
+            let revoked_tokens = self.revoked_tokens.clone();
+            let shutdown_rx = self.shutdown_tx.subscribe();
+            let heartbeat_tx = self.heartbeat_tx.clone().unwrap();
+
+            tokio::spawn(async move {
+                Daemon::poll_process(
+                    worker,
+                    rx,
+                    waiting_tx,
+                    revoked_tokens,
+                    shutdown_rx,
+                    stop_rx,
+                    heartbeat_tx,
+                )
+                .await
+            });
+            self.stop_txs.push(stop_tx);
+        }
+
+        // stopped workers finish their current message first
+        while self.stop_txs.len() > size {
+            let _ = self.stop_txs.pop().unwrap().send(());
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.stop_txs.len()
+    }
+
+    /// Consumes a revoked waiting token, if any, in place of the received one.
+    /// Either the received token is the revoked one, or the revoked one has already made
+    /// a message to be received, which another idle worker takes instead of its own.
+    fn take_revoked_token(&self) -> bool {
+        self.revoked_tokens
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    async fn shutdown(&mut self, mut heartbeat_rx: mpsc::Receiver<()>) {
+        self.heartbeat_tx = None;
+        if self.shutdown_tx.send(()).is_ok() {
+            let _ = heartbeat_rx.recv().await;
+        } else {
+            error!("Failed to send shutdown message to worker.");
+        }
+    }
 }
 
 impl Daemon {
-    pub async fn new(config: Config) -> Self {
-        Daemon {
+    /// Fails with `FatalError::Config` if the API client cannot be built from the configuration,
+    /// e.g. because of unreadable TLS files or an invalid gRPC URL.
+    pub async fn new(config: Config) -> Result<Self, FatalError> {
+        let api = new_api(config.clone()).map_err(FatalError::Config)?;
+        // input and output queues share the same endpoint and credentials, so one client is enough
+        let client = AwsSqs::build_client(&config).await;
+        let output_sqs: Option<Arc<dyn Sqs + Send + Sync>> = match &config.output_sqs_url {
+            None => None,
+            Some(u) => Some(Arc::new(AwsSqs::new(client.clone(), u.to_string()))),
+        };
+        Ok(Daemon {
             config: config.clone(),
-            sqs: Box::new(AwsSqs::new(config.sqs_url.to_string(), &config).await),
-            api: Box::new(ApiImpl::new(config.clone())),
-        }
+            sqs: Arc::new(AwsSqs::new(client, config.sqs_url.to_string())),
+            output_sqs,
+            api: Arc::new(ReloadableApi::new(api)),
+            metrics: Metrics::new(config.sqs_url.as_str()),
+            state: Arc::new(State::new()),
+        })
+    }
+
+    pub fn state(&self) -> Arc<State> {
+        self.state.clone()
+    }
+
+    pub fn health(&self) -> Health {
+        Health::new(
+            self.state.clone(),
+            self.sqs.clone(),
+            self.api.clone(),
+            self.config.api_health_url.clone(),
+            Duration::from_secs(self.config.liveness_timeout_seconds),
+        )
     }
 
     pub async fn run(
-        self,
+        mut self,
         mut shutdown_rx: broadcast::Receiver<()>,
+        mut reload_rx: mpsc::Receiver<Config>,
         _heartbeat_tx: mpsc::Sender<()>,
-    ) -> Result<()> {
+    ) -> Result<(), FatalError> {
+        // a missing queue or invalid credentials are not recovered by retrying
+        match self.sqs.check().await {
+            Ok(()) => {}
+            Err(e @ (SqsError::QueueNotFound(_) | SqsError::Auth(_))) => return Err(e.into()),
+            Err(e) => warn!("Failed to check SQS queue. ({})", e),
+        }
+
         // wait for health check
         if let Some(url) = &self.config.api_health_url {
-            tokio::select! {
-                result = Self::healthcheck(self.api.borrow(), url, self.config.api_health_interval_seconds) => {
-                    match result {
-                        Ok(v) => v,
-                        Err(e) => panic("Failed to pass health check of the API.", e),
+            let healthcheck = Self::healthcheck(
+                self.api.as_ref(),
+                url,
+                self.config.api_health_interval_seconds,
+                &self.state,
+            );
+            let healthcheck = async {
+                match self.config.api_health_timeout_seconds {
+                    None => healthcheck.await.map_err(FatalError::from),
+                    Some(seconds) => {
+                        let limit = Duration::from_secs(seconds);
+                        timeout(limit, healthcheck)
+                            .await
+                            .map_err(|_| FatalError::HealthCheckTimeout(limit))?
+                            .map_err(FatalError::from)
                     }
-                },
+                }
+            };
+            tokio::select! {
+                result = healthcheck => result?,
                 _ = shutdown_rx.recv() => return Ok(()),
             }
         }
 
+        let max_receive_count = match self.sqs.max_receive_count().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to get redrive policy of SQS. ({:?})", e);
+                None
+            }
+        };
+
         // create workers
-        let (tx, rx) = async_channel::bounded::<Message>(self.config.num_workers);
-        let (worker_waiting_tx, mut worker_waiting_rx) =
-            mpsc::channel::<()>(self.config.num_workers);
+        let num_workers = self.config.worker_count();
+        let (tx, rx) = async_channel::bounded::<Message>(num_workers);
+        // each idle worker sends one token, so the number of tokens is bounded by the pool size,
+        // which can grow on reload
+        let (worker_waiting_tx, mut worker_waiting_rx) = mpsc::unbounded_channel::<()>();
         let (worker_shutdown_tx, _) = broadcast::channel(1);
-        let (worker_heartbeat_tx, mut worker_heartbeat_rx) = mpsc::channel::<()>(1);
-
-        for _ in 0..self.config.num_workers {
-            let config = self.config.clone();
-            let sqs = Box::new(AwsSqs::new(config.sqs_url.to_string(), &config).await);
-            let api = Box::new(ApiImpl::new(self.config.clone()));
-            let output_sqs: Option<Box<dyn Sqs + Send + Sync>> = match &self.config.output_sqs_url {
-                None => None,
-                Some(u) => Some(Box::new(AwsSqs::new(u.to_string(), &config).await)),
-            };
-            let rx = rx.clone();
-            let waiting_tx = worker_waiting_tx.clone();
-            let shutdown_rx = worker_shutdown_tx.subscribe();
-            let heartbeat_tx = worker_heartbeat_tx.clone();
+        let (worker_heartbeat_tx, worker_heartbeat_rx) = mpsc::channel::<()>(1);
 
-            tokio::spawn(async move {
-                Self::poll_process(
-                    sqs,
-                    api,
-                    output_sqs,
-                    rx,
-                    waiting_tx,
-                    shutdown_rx,
-                    heartbeat_tx,
+        let (api, limiter): (Arc<dyn Api + Send + Sync>, _) = match self.config.concurrency_mode {
+            ConcurrencyMode::Fixed => {
+                self.metrics.concurrency_limit.set(num_workers as i64);
+                (self.api.clone(), None)
+            }
+            ConcurrencyMode::Adaptive => {
+                let limiter = Arc::new(Limiter::new(&self.config));
+                info!("Initial concurrency limit is {}.", limiter.limit());
+                (
+                    Arc::new(LimitedApi::new(self.api.clone(), limiter.clone())),
+                    Some(limiter),
                 )
-                .await
-            });
-            let _ = worker_waiting_tx.send(()).await;
-        }
+            }
+        };
 
-        drop(worker_heartbeat_tx);
+        let mut pool = WorkerPool {
+            worker: Worker {
+                id: 0,
+                queue: self.config.sqs_url.to_string(),
+                sqs: self.sqs.clone(),
+                api,
+                output_sqs: self.output_sqs.clone(),
+                metrics: self.metrics.clone(),
+                max_receive_count,
+                invalid_message_policy: self.config.invalid_message_policy,
+                response_transform: self.config.response_transform(),
+                limiter: limiter.clone(),
+            },
+            rx,
+            waiting_tx: worker_waiting_tx.clone(),
+            revoked_tokens: Arc::new(AtomicUsize::new(0)),
+            shutdown_tx: worker_shutdown_tx,
+            heartbeat_tx: Some(worker_heartbeat_tx),
+            stop_txs: vec![],
+            next_id: 0,
+        };
+        pool.resize(num_workers);
 
         // receive SQS message
+        let mut throttle_backoff = None;
         loop {
-            let _ = worker_waiting_rx.recv().await;
+            // keep ticking while all workers are busy
+            loop {
+                self.state.tick();
+                while let Ok(config) = reload_rx.try_recv() {
+                    self.reload(config, &mut pool, &limiter);
+                }
+                if timeout(TICK_INTERVAL, worker_waiting_rx.recv())
+                    .await
+                    .is_ok()
+                    && !pool.take_revoked_token()
+                {
+                    break;
+                }
+            }
+
+            // keep ticking while paused, in-flight messages are still processed by workers
+            if self.state.is_paused() {
+                info!("Paused receiving messages.");
+                while self.state.is_paused() && !self.state.is_draining() {
+                    self.state.tick();
+                    let _ = timeout(TICK_INTERVAL, self.state.wait_resumed()).await;
+                }
+                if !self.state.is_paused() {
+                    info!("Resumed receiving messages.");
+                }
+            }
+            self.state.tick();
             tokio::select! {
                 result = self.sqs.receive_messages() => {
+                    if !matches!(result, Err(SqsError::Throttled(_))) {
+                        throttle_backoff = None;
+                    }
                     match result {
                         Ok(response) => {
                             match response {
@@ -96,10 +296,11 @@ impl Daemon {
                                     if messages.is_empty() {
                                         warn!("Empty message received. Sleep.");
                                         Self::sleep(self.config.sleep_msec).await;
-                                        if let Err(e) = worker_waiting_tx.send(()).await {
+                                        if let Err(e) = worker_waiting_tx.send(()) {
                                            error!("Failed to send waiting queue. ({:?})", e);
                                         }
                                     } else {
+                                        self.metrics.messages_received.inc_by(messages.len() as u64);
                                         for message in messages {
                                             debug!("Received message: {:?}", message);
 
@@ -107,43 +308,101 @@ impl Daemon {
                                             if r.is_err() {
                                                 error!("Failed to send received message to worker.");
                                             }
-                                            r?;
+                                            r.map_err(anyhow::Error::new)?;
                                         }
                                     }
                                 }
                                 None => {
                                     debug!("No received message. Sleep.");
                                     Self::sleep(self.config.sleep_msec).await;
-                                    if let Err(e) = worker_waiting_tx.send(()).await {
+                                    if let Err(e) = worker_waiting_tx.send(()) {
                                         error!("Failed to send waiting queue. ({:?})", e);
                                     }
                                 }
                             }
                         },
+                        Err(e @ SqsError::QueueNotFound(_)) => {
+                            error!("{} Stop receiving messages.", e);
+                            self.state.drain();
+                            pool.shutdown(worker_heartbeat_rx).await;
+                            return Err(e.into());
+                        }
                         Err(e) => {
-                            error!("Failed to receive messages from SQS. ({:?})", e);
-                            Self::sleep(self.config.sleep_msec).await;
-                            if let Err(e) = worker_waiting_tx.send(()).await {
+                            match &e {
+                                SqsError::Throttled(_) => {
+                                    let backoff = Self::throttle_backoff(throttle_backoff, Duration::from_millis(self.config.sleep_msec));
+                                    warn!("{} Back off for {:?}.", e, backoff);
+                                    throttle_backoff = Some(backoff);
+                                    sleep(backoff).await;
+                                }
+                                SqsError::Connection(_) | SqsError::Timeout(_) => {
+                                    warn!("{} Retry.", e);
+                                    Self::sleep(self.config.sleep_msec).await;
+                                }
+                                _ => {
+                                    error!("Failed to receive messages from SQS. ({})", e);
+                                    Self::sleep(self.config.sleep_msec).await;
+                                }
+                            }
+                            if let Err(e) = worker_waiting_tx.send(()) {
                                 error!("Failed to send waiting queue. ({:?})", e);
                             }
                         }
                     }
                 }
                 _ = shutdown_rx.recv() => {
-                    let r = worker_shutdown_tx.send(());
-                    if r.is_ok() {
-                        let _ = worker_heartbeat_rx.recv().await;
-                    } else {
-                        error!("Failed to send shutdown message to worker.");
-                    }
+                    self.state.drain();
+                    pool.shutdown(worker_heartbeat_rx).await;
                     return Ok(());
                 }
             }
         }
     }
 
-    async fn healthcheck(api: &'_ (dyn Api + Send + Sync), url: &Url, seconds: u64) -> Result<()> {
+    /// Applies a reloaded configuration without dropping in-flight messages.
+    fn reload(&mut self, config: Config, pool: &mut WorkerPool, limiter: &Option<Arc<Limiter>>) {
+        let (config, ignored) = self.config.reload(config);
+        for field in ignored {
+            warn!(
+                "Changing `{}` requires restart. Keep the current value.",
+                field
+            );
+        }
+
+        match new_api(config.clone()) {
+            Ok(api) => self.api.replace(api),
+            Err(e) => {
+                error!("Failed to reload configuration. ({:?})", e);
+                return;
+            }
+        }
+
+        if let Some(limiter) = limiter {
+            limiter.reconfigure(&config);
+        } else {
+            self.metrics
+                .concurrency_limit
+                .set(config.worker_count() as i64);
+        }
+
+        let size = config.worker_count();
+        if size != pool.size() {
+            info!("Resize workers. ({} -> {})", pool.size(), size);
+            pool.resize(size);
+        }
+
+        self.config = config;
+        info!("Reloaded configuration.");
+    }
+
+    async fn healthcheck(
+        api: &'_ (dyn Api + Send + Sync),
+        url: &Url,
+        seconds: u64,
+        state: &State,
+    ) -> Result<()> {
         loop {
+            state.tick();
             if api.get(url).await.is_ok() {
                 break;
             }
@@ -155,57 +414,158 @@ impl Daemon {
     }
 
     async fn poll_process(
-        sqs: Box<dyn Sqs + Send + Sync>,
-        api: Box<dyn Api + Send + Sync>,
-        output_sqs: Option<Box<dyn Sqs + Send + Sync>>,
+        worker: Worker,
         rx: async_channel::Receiver<Message>,
-        waiting_tx: mpsc::Sender<()>,
+        waiting_tx: mpsc::UnboundedSender<()>,
+        revoked_tokens: Arc<AtomicUsize>,
         mut shutdown_rx: broadcast::Receiver<()>,
+        mut stop_rx: oneshot::Receiver<()>,
         _heartbeat_tx: mpsc::Sender<()>,
     ) -> Result<()> {
         loop {
+            // in adaptive mode, a worker asks for a message only when it may call the API,
+            // so that received messages do not wait for a permit while their visibility timeouts run
+            let _permit = match &worker.limiter {
+                None => None,
+                Some(limiter) => tokio::select! {
+                    permit = limiter.acquire() => Some(permit),
+                    _ = shutdown_rx.recv() => return Ok(()),
+                    _ = &mut stop_rx => return Ok(()),
+                },
+            };
+
+            worker.metrics.idle_workers.inc();
+            if let Err(e) = waiting_tx.send(()) {
+                error!("Failed to send waiting queue. ({:?})", e);
+            }
             tokio::select! {
                 result = rx.recv() => {
+                    worker.metrics.idle_workers.dec();
                     match result {
                         Ok(message) => {
-                            debug!("Processing message: {:?}", message);
-
-                            if message.check_hash() {
-                                match Self::process_message(message.clone(), sqs.borrow(), api.borrow(), &output_sqs).await {
-                                    Ok(()) => debug!("Succeeded to process message. ({})", message.message_id),
-                                    Err(e) => error!("Failed to process message. ({}, {:?})", message.message_id, e),
-                                };
-                            } else {
-                                warn!("Mismatch message MD5 digest. ({})", message.message_id);
+                            // at the ERROR level, so that the filter of `--rust-log` keeps
+                            // the span for every event in it
+                            let span = error_span!(
+                                "message",
+                                message_id = %message.message_id,
+                                queue = %worker.queue,
+                                receive_count = field::Empty,
+                                worker_id = worker.id,
+                                "otel.kind" = "consumer",
+                            );
+                            span.set_parent(telemetry::extract(&message.attributes));
+                            if let Some(count) = message.receive_count {
+                                span.record("receive_count", count);
                             }
+                            Self::handle_message(&worker, message).instrument(span).await;
                         }
                         Err(e) => {
                             error!("Failed to receive message. ({:?})", e);
                         }
                     }
+                }
+                _ = shutdown_rx.recv() => {
+                    worker.metrics.idle_workers.dec();
+                    return Ok(());
+                },
+                _ = &mut stop_rx => {
+                    worker.metrics.idle_workers.dec();
+                    revoked_tokens.fetch_add(1, Ordering::SeqCst);
+                    return Ok(());
+                },
+            }
+        }
+    }
+
+    async fn handle_message(worker: &Worker, message: Message) {
+        let metrics = &worker.metrics;
+        debug!("Processing message: {:?}", message);
+
+        if !message.check_hash() {
+            warn!("Mismatch message MD5 digest.");
+            metrics.md5_mismatches.inc();
+            return;
+        }
 
-                    if let Err(e) = waiting_tx.send(()).await {
-                        error!("Failed to send waiting queue. ({:?})", e);
+        metrics.in_flight_messages.inc();
+        match Self::process_message(
+            message.clone(),
+            worker.sqs.borrow(),
+            worker.api.borrow(),
+            &worker.output_sqs,
+            &worker.response_transform,
+            metrics,
+        )
+        .await
+        {
+            Ok(()) => {
+                debug!("Succeeded to process message.");
+                metrics.messages_processed.inc();
+                if let Some(age) = message.age() {
+                    metrics.message_age.observe(age.as_secs_f64());
+                }
+            }
+            Err(e) => {
+                error!("Failed to process message. ({:?})", e);
+                metrics.messages_failed.inc();
+                let is_invalid = matches!(
+                    e.downcast_ref::<ApiError>(),
+                    Some(ApiError::InvalidMessage(_))
+                ) || e.is::<TransformError>();
+                if is_invalid && worker.invalid_message_policy == InvalidMessagePolicy::Delete {
+                    warn!("Invalid message will be deleted.");
+                    if let Err(e) = worker.sqs.delete_message(message.receipt_handle).await {
+                        error!("Failed to delete message. ({:?})", e);
+                    }
+                } else if let (Some(count), Some(max)) =
+                    (message.receive_count, worker.max_receive_count)
+                {
+                    if count >= max {
+                        warn!("Message will be moved to dead-letter queue.");
+                        metrics.messages_dead_lettered.inc();
                     }
                 }
-                _ = shutdown_rx.recv() => return Ok(()),
             }
-        }
+        };
+        metrics.in_flight_messages.dec();
     }
 
     async fn process_message(
         message: Message,
         sqs: &'_ (dyn Sqs + Send + Sync),
         api: &'_ (dyn Api + Send + Sync),
-        output_sqs: &Option<Box<dyn Sqs + Send + Sync>>,
+        output_sqs: &Option<Arc<dyn Sqs + Send + Sync>>,
+        response_transform: &ResponseTransform,
+        metrics: &Metrics,
     ) -> Result<()> {
-        let (is_succeeded, res) = api.post(message.body.clone(), &message.message_id).await?;
+        let timer = metrics.api_request_duration.start_timer();
+        let (is_succeeded, res) = api.post(&message).await?;
+        timer.observe_duration();
         if !is_succeeded {
             return Err(anyhow!("API returns failed status response."));
         }
 
-        if output_sqs.is_some() {
-            output_sqs.as_ref().unwrap().send_message(res).await?;
+        if let Some(output_sqs) = output_sqs {
+            // `--invalid-message-policy` decides whether the message is retried, which calls
+            // the API again
+            match response_transform.apply(&res, &message) {
+                Ok(body) => {
+                    let output = OutputMessage {
+                        body,
+                        attributes: telemetry::inject(&Span::current()),
+                        trace_header: message.trace_header.clone(),
+                    };
+                    output_sqs.send_message(output).await?;
+                }
+                Err(error) => {
+                    metrics.output_transform_failures.inc();
+                    return Err(TransformError {
+                        error,
+                        response: res,
+                    }
+                    .into());
+                }
+            }
         }
 
         sqs.delete_message(message.receipt_handle).await?;
@@ -213,6 +573,13 @@ impl Daemon {
         Ok(())
     }
 
+    /// Doubles the previous backoff on each consecutive throttling, up to `MAX_THROTTLE_BACKOFF`.
+    fn throttle_backoff(previous: Option<Duration>, base: Duration) -> Duration {
+        previous.map_or(base.max(MIN_THROTTLE_BACKOFF), |previous| {
+            (previous * 2).min(MAX_THROTTLE_BACKOFF)
+        })
+    }
+
     async fn sleep(milliseconds: u64) {
         sleep(Duration::from_millis(milliseconds)).await;
     }
@@ -221,13 +588,15 @@ impl Daemon {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::domain::error::ApiError;
     use crate::domain::message::*;
     use crate::infra::api::*;
     use crate::infra::sqs::*;
-    use anyhow::anyhow;
     use mockall::predicate::*;
     use std::borrow::Borrow;
+    use std::collections::HashMap;
     use std::str::FromStr;
+    use structopt::StructOpt;
 
     #[tokio::test]
     async fn test_process_message_with_output() {
@@ -242,8 +611,8 @@ mod tests {
         let sqs: Box<dyn Sqs + Send + Sync> = Box::new(sqs);
 
         let mut api = MockApi::new();
-        api.expect_post().times(1).returning(|_, message_id| {
-            assert_eq!(message_id, "message_id");
+        api.expect_post().times(1).returning(|message| {
+            assert_eq!(message.message_id, "message_id");
             Ok((true, "result".to_string()))
         });
         let api: Box<dyn Api + Send + Sync> = Box::new(api);
@@ -252,22 +621,93 @@ mod tests {
         output_sqs.expect_receive_messages().times(0);
         output_sqs
             .expect_send_message()
-            .with(eq("result".to_string()))
+            .with(eq(OutputMessage {
+                body: "result".to_string(),
+                attributes: HashMap::new(),
+                trace_header: Some("Root=1-5759e988-bd862e3fe1be46a994272793".to_string()),
+            }))
             .times(1)
             .returning(|_| Ok(()));
         output_sqs.expect_delete_message().times(0);
-        let output_sqs: Option<Box<dyn Sqs + Send + Sync>> = Some(Box::new(output_sqs));
+        let output_sqs: Option<Arc<dyn Sqs + Send + Sync>> = Some(Arc::new(output_sqs));
 
         let message = Message {
             receipt_handle: "receipt_handle".to_string(),
             body: "{\"key1\": 1}".to_string(),
             md5_of_body: "dummy".to_string(),
             message_id: "message_id".to_string(),
+            attributes: HashMap::new(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: Some("Root=1-5759e988-bd862e3fe1be46a994272793".to_string()),
         };
 
-        Daemon::process_message(message, sqs.borrow(), api.borrow(), &output_sqs)
-            .await
-            .unwrap();
+        Daemon::process_message(
+            message,
+            sqs.borrow(),
+            api.borrow(),
+            &output_sqs,
+            &ResponseTransform::default(),
+            &Metrics::new("test"),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_process_message_with_transform() {
+        let transform = ResponseTransform {
+            extract: Some("result".parse().unwrap()),
+            ..Default::default()
+        };
+        let message = Message {
+            receipt_handle: "receipt_handle".to_string(),
+            body: "{\"key1\": 1}".to_string(),
+            md5_of_body: "dummy".to_string(),
+            message_id: "message_id".to_string(),
+            attributes: HashMap::new(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
+        };
+
+        // forwarded after transformation, or failed without forwarding nor deleting
+        for (response, output) in [(r#"{"result": "done"}"#, Some("done")), ("oops", None)] {
+            let mut sqs = MockSqs::new();
+            sqs.expect_delete_message()
+                .with(eq("receipt_handle".to_string()))
+                .times(output.map_or(0, |_| 1))
+                .returning(|_| Ok(()));
+            let sqs: Box<dyn Sqs + Send + Sync> = Box::new(sqs);
+
+            let mut api = MockApi::new();
+            api.expect_post()
+                .times(1)
+                .returning(move |_| Ok((true, response.to_string())));
+            let api: Box<dyn Api + Send + Sync> = Box::new(api);
+
+            let mut output_sqs = MockSqs::new();
+            output_sqs
+                .expect_send_message()
+                .withf(move |sent| Some(sent.body.as_str()) == output)
+                .times(output.map_or(0, |_| 1))
+                .returning(|_| Ok(()));
+            let output_sqs: Option<Arc<dyn Sqs + Send + Sync>> = Some(Arc::new(output_sqs));
+
+            let result = Daemon::process_message(
+                message.clone(),
+                sqs.borrow(),
+                api.borrow(),
+                &output_sqs,
+                &transform,
+                &Metrics::new("test"),
+            )
+            .await;
+            assert_eq!(
+                result.map_err(|e| e.is::<TransformError>()),
+                output.map_or(Err(true), |_| Ok(()))
+            );
+        }
     }
 
     #[tokio::test]
@@ -283,8 +723,8 @@ mod tests {
         let sqs: Box<dyn Sqs + Send + Sync> = Box::new(sqs);
 
         let mut api = MockApi::new();
-        api.expect_post().times(1).returning(|_, message_id| {
-            assert_eq!(message_id, "message_id");
+        api.expect_post().times(1).returning(|message| {
+            assert_eq!(message.message_id, "message_id");
             Ok((true, "result".to_string()))
         });
         let api: Box<dyn Api + Send + Sync> = Box::new(api);
@@ -296,11 +736,22 @@ mod tests {
             body: "{\"key1\": 1}".to_string(),
             md5_of_body: "dummy".to_string(),
             message_id: "message_id".to_string(),
+            attributes: HashMap::new(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
         };
 
-        Daemon::process_message(message, sqs.borrow(), api.borrow(), &output_sqs)
-            .await
-            .unwrap();
+        Daemon::process_message(
+            message,
+            sqs.borrow(),
+            api.borrow(),
+            &output_sqs,
+            &ResponseTransform::default(),
+            &Metrics::new("test"),
+        )
+        .await
+        .unwrap();
     }
 
     #[tokio::test]
@@ -310,8 +761,8 @@ mod tests {
         let sqs: Box<dyn Sqs + Send + Sync> = Box::new(sqs);
 
         let mut api = MockApi::new();
-        api.expect_post().times(1).returning(|_, message_id| {
-            assert_eq!(message_id, "message_id");
+        api.expect_post().times(1).returning(|message| {
+            assert_eq!(message.message_id, "message_id");
             Ok((false, "result".to_string()))
         });
         let api: Box<dyn Api + Send + Sync> = Box::new(api);
@@ -320,20 +771,29 @@ mod tests {
         output_sqs.expect_receive_messages().times(0);
         output_sqs.expect_send_message().times(0);
         output_sqs.expect_delete_message().times(0);
-        let output_sqs: Option<Box<dyn Sqs + Send + Sync>> = Some(Box::new(output_sqs));
+        let output_sqs: Option<Arc<dyn Sqs + Send + Sync>> = Some(Arc::new(output_sqs));
 
         let message = Message {
             receipt_handle: "receipt_handle".to_string(),
             body: "{\"key1\": 1}".to_string(),
             md5_of_body: "dummy".to_string(),
             message_id: "message_id".to_string(),
+            attributes: HashMap::new(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
         };
 
-        assert!(
-            Daemon::process_message(message, sqs.borrow(), api.borrow(), &output_sqs)
-                .await
-                .is_err()
-        );
+        assert!(Daemon::process_message(
+            message,
+            sqs.borrow(),
+            api.borrow(),
+            &output_sqs,
+            &ResponseTransform::default(),
+            &Metrics::new("test"),
+        )
+        .await
+        .is_err());
     }
 
     #[tokio::test]
@@ -341,7 +801,7 @@ mod tests {
         let mut api = MockApi::new();
         api.expect_get()
             .times(3)
-            .returning(|_| Err(anyhow!("Error")))
+            .returning(|_| Err(ApiError::Connection("Error".to_string())))
             .times(1)
             .returning(|_| Ok(()));
         let api: Box<dyn Api + Send + Sync> = Box::new(api);
@@ -350,8 +810,285 @@ mod tests {
             api.borrow(),
             &Url::from_str("http://dummy:1234/").unwrap(),
             1,
+            &State::new(),
         )
         .await
         .unwrap();
     }
+
+    fn daemon(sqs: MockSqs, api: MockApi, api_health_url: Option<&str>) -> Daemon {
+        let mut config = Config::from_iter(&[
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-url",
+            "http://localhost:4000/",
+        ]);
+        config.api_health_url = api_health_url.map(|url| Url::from_str(url).unwrap());
+        config.api_health_interval_seconds = 0;
+        config.api_health_timeout_seconds = Some(1);
+        Daemon {
+            config,
+            sqs: Arc::new(sqs),
+            output_sqs: None,
+            api: Arc::new(ReloadableApi::new(Arc::new(api))),
+            metrics: Metrics::new("test"),
+            state: Arc::new(State::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_if_queue_not_found() {
+        let mut sqs = MockSqs::new();
+        sqs.expect_check()
+            .times(1)
+            .returning(|| Err(SqsError::QueueNotFound("sqs".to_string())));
+        sqs.expect_receive_messages().times(0);
+
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_reload_tx, reload_rx) = mpsc::channel(1);
+        let (heartbeat_tx, _heartbeat_rx) = mpsc::channel(1);
+        let e = daemon(sqs, MockApi::new(), None)
+            .run(shutdown_rx, reload_rx, heartbeat_tx)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(e, FatalError::QueueNotFound(_)));
+        assert_eq!(e.exit_code(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_if_health_check_times_out() {
+        let mut sqs = MockSqs::new();
+        sqs.expect_check().returning(|| Ok(()));
+        sqs.expect_receive_messages().times(0);
+        let mut api = MockApi::new();
+        api.expect_get()
+            .returning(|_| Err(ApiError::Connection("Error".to_string())));
+
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_reload_tx, reload_rx) = mpsc::channel(1);
+        let (heartbeat_tx, _heartbeat_rx) = mpsc::channel(1);
+        let e = daemon(sqs, api, Some("http://dummy:1234/"))
+            .run(shutdown_rx, reload_rx, heartbeat_tx)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(e, FatalError::HealthCheckTimeout(_)));
+        assert_eq!(e.exit_code(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_if_queue_is_deleted() {
+        let mut sqs = MockSqs::new();
+        sqs.expect_check().returning(|| Ok(()));
+        sqs.expect_max_receive_count().returning(|| Ok(None));
+        sqs.expect_receive_messages()
+            .times(1)
+            .returning(|| Err(SqsError::QueueNotFound("sqs".to_string())));
+
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_reload_tx, reload_rx) = mpsc::channel(1);
+        let (heartbeat_tx, _heartbeat_rx) = mpsc::channel(1);
+        let e = daemon(sqs, MockApi::new(), None)
+            .run(shutdown_rx, reload_rx, heartbeat_tx)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(e, FatalError::QueueNotFound(_)));
+    }
+
+    fn worker(sqs: MockSqs, api: MockApi) -> Worker {
+        Worker {
+            id: 0,
+            queue: "test".to_string(),
+            sqs: Arc::new(sqs),
+            api: Arc::new(api),
+            output_sqs: None,
+            metrics: Metrics::new("test"),
+            max_receive_count: None,
+            invalid_message_policy: InvalidMessagePolicy::Retry,
+            response_transform: ResponseTransform::default(),
+            limiter: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_invalid_message() {
+        let message = Message {
+            receipt_handle: "receipt_handle".to_string(),
+            body: "hoge".to_string(),
+            md5_of_body: "ea703e7aa1efda0064eaa507d9e8ab7e".to_string(),
+            message_id: "message_id".to_string(),
+            attributes: HashMap::new(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
+        };
+        for (policy, deletes) in [
+            (InvalidMessagePolicy::Retry, 0),
+            (InvalidMessagePolicy::Delete, 1),
+        ] {
+            let mut sqs = MockSqs::new();
+            sqs.expect_delete_message()
+                .times(deletes)
+                .returning(|receipt_handle| {
+                    assert_eq!(receipt_handle, "receipt_handle");
+                    Ok(())
+                });
+            let mut api = MockApi::new();
+            api.expect_post()
+                .times(1)
+                .returning(|_| Err(ApiError::InvalidMessage("missing field".to_string())));
+            let worker = Worker {
+                invalid_message_policy: policy,
+                ..worker(sqs, api)
+            };
+
+            // the expectations of the mocks are checked when the worker is dropped
+            Daemon::handle_message(&worker, message.clone()).await;
+        }
+
+        // so is a message whose API response cannot be transformed
+        for (policy, deletes) in [
+            (InvalidMessagePolicy::Retry, 0),
+            (InvalidMessagePolicy::Delete, 1),
+        ] {
+            let mut sqs = MockSqs::new();
+            sqs.expect_delete_message()
+                .times(deletes)
+                .returning(|_| Ok(()));
+            let mut api = MockApi::new();
+            api.expect_post()
+                .times(1)
+                .returning(|_| Ok((true, "not json".to_string())));
+            let mut output_sqs = MockSqs::new();
+            output_sqs.expect_send_message().times(0);
+            let worker = Worker {
+                invalid_message_policy: policy,
+                output_sqs: Some(Arc::new(output_sqs)),
+                response_transform: ResponseTransform {
+                    extract: Some("result".parse().unwrap()),
+                    ..Default::default()
+                },
+                ..worker(sqs, api)
+            };
+
+            Daemon::handle_message(&worker, message.clone()).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_workers_wait_for_permit_before_receiving() {
+        let mut config = Config::from_iter(&[
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-url",
+            "http://localhost:4000/",
+            "--concurrency-mode",
+            "adaptive",
+            "--num-workers",
+            "2",
+            "--max-concurrency",
+            "4",
+        ]);
+        let limiter = Arc::new(Limiter::new(&config));
+        let (_tx, rx) = async_channel::bounded(4);
+        let (waiting_tx, mut waiting_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let (heartbeat_tx, _heartbeat_rx) = mpsc::channel(1);
+        let mut pool = WorkerPool {
+            worker: Worker {
+                limiter: Some(limiter.clone()),
+                ..worker(MockSqs::new(), MockApi::new())
+            },
+            rx,
+            waiting_tx,
+            revoked_tokens: Arc::new(AtomicUsize::new(0)),
+            shutdown_tx,
+            heartbeat_tx: Some(heartbeat_tx),
+            stop_txs: vec![],
+            next_id: 0,
+        };
+        pool.resize(4);
+
+        // only the workers with a permit ask for a message
+        for _ in 0..2 {
+            waiting_rx.recv().await.unwrap();
+        }
+        assert!(timeout(Duration::from_millis(100), waiting_rx.recv())
+            .await
+            .is_err());
+
+        config.min_concurrency = 3;
+        limiter.reconfigure(&config);
+        assert!(timeout(Duration::from_secs(1), waiting_rx.recv())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stopped_idle_workers_revoke_waiting_tokens() {
+        let (_tx, rx) = async_channel::bounded(2);
+        let (waiting_tx, mut waiting_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let (heartbeat_tx, _heartbeat_rx) = mpsc::channel(1);
+        let mut pool = WorkerPool {
+            worker: worker(MockSqs::new(), MockApi::new()),
+            rx,
+            waiting_tx,
+            revoked_tokens: Arc::new(AtomicUsize::new(0)),
+            shutdown_tx,
+            heartbeat_tx: Some(heartbeat_tx),
+            stop_txs: vec![],
+            next_id: 0,
+        };
+        pool.resize(3);
+        for _ in 0..3 {
+            waiting_rx.recv().await.unwrap();
+        }
+
+        pool.resize(1);
+        sleep(Duration::from_millis(100)).await;
+        assert!(pool.take_revoked_token());
+        assert!(pool.take_revoked_token());
+        assert!(!pool.take_revoked_token());
+    }
+
+    #[tokio::test]
+    async fn test_new_fails_with_config_error() {
+        let mut config = Config::from_iter(&[
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-url",
+            "https://localhost:4000/",
+        ]);
+        config.api_tls_ca_cert = Some(std::path::PathBuf::from("/nonexistent/ca.pem"));
+
+        let e = Daemon::new(config).await.err().unwrap();
+        assert!(matches!(e, FatalError::Config(_)));
+        assert_eq!(e.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_throttle_backoff() {
+        let base = Duration::from_secs(1);
+        let mut backoff = None;
+        let backoffs: Vec<u64> = (0..7)
+            .map(|_| {
+                backoff = Some(Daemon::throttle_backoff(backoff, base));
+                backoff.unwrap().as_secs()
+            })
+            .collect();
+
+        assert_eq!(backoffs, vec![1, 2, 4, 8, 16, 20, 20]);
+
+        // `--sleep-msec 0` does not retry immediately
+        assert_eq!(
+            Daemon::throttle_backoff(None, Duration::ZERO),
+            Duration::from_millis(100)
+        );
+    }
 }
@@ -0,0 +1,203 @@
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use tokio::time::{timeout_at, Duration, Instant};
+use url::Url;
+
+use crate::app::state::State;
+use crate::infra::api::Api;
+use crate::infra::sqs::Sqs;
+
+/// Total timeout of the readiness checks, which run concurrently. It is shorter than the default
+/// probe timeout of Kubernetes (1 second), so that a slow check fails the probe with its reason.
+const CHECK_TIMEOUT: Duration = Duration::from_millis(800);
+
+/// Liveness and readiness checks reflecting the actual state of the daemon.
+#[derive(Clone)]
+pub struct Health {
+    state: Arc<State>,
+    sqs: Arc<dyn Sqs + Send + Sync>,
+    api: Arc<dyn Api + Send + Sync>,
+    api_health_url: Option<Url>,
+    liveness_timeout: Duration,
+    check_timeout: Duration,
+}
+
+impl Health {
+    pub fn new(
+        state: Arc<State>,
+        sqs: Arc<dyn Sqs + Send + Sync>,
+        api: Arc<dyn Api + Send + Sync>,
+        api_health_url: Option<Url>,
+        liveness_timeout: Duration,
+    ) -> Self {
+        Health {
+            state,
+            sqs,
+            api,
+            api_health_url,
+            liveness_timeout,
+            check_timeout: CHECK_TIMEOUT,
+        }
+    }
+
+    /// The process is alive and the receive loop ticked recently.
+    pub fn live(&self) -> Result<()> {
+        let elapsed = self.state.since_last_tick();
+        if elapsed > self.liveness_timeout {
+            return Err(anyhow!(
+                "Receive loop has not ticked for {} seconds.",
+                elapsed.as_secs()
+            ));
+        }
+        Ok(())
+    }
+
+    /// The daemon is not draining, the SQS queue is reachable and the API health check passes.
+    pub async fn ready(&self) -> Result<()> {
+        if self.state.is_draining() {
+            return Err(anyhow!("Daemon is draining."));
+        }
+
+        let deadline = Instant::now() + self.check_timeout;
+        let sqs = async {
+            timeout_at(deadline, self.sqs.check())
+                .await
+                .map_err(|_| anyhow!("SQS check timed out."))?
+                .map_err(|e| anyhow!("SQS is not reachable. ({})", e))
+        };
+        let api = async {
+            match &self.api_health_url {
+                None => Ok(()),
+                Some(url) => timeout_at(deadline, self.api.get(url))
+                    .await
+                    .map_err(|_| anyhow!("API health check timed out."))?
+                    .map_err(|e| anyhow!("API health check failed. ({})", e)),
+            }
+        };
+        tokio::try_join!(sqs, api)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::error::{ApiError, SqsError};
+    use crate::domain::message::Message;
+    use crate::infra::api::MockApi;
+    use crate::infra::sqs::MockSqs;
+    use async_trait::async_trait;
+    use std::str::FromStr;
+
+    fn health(sqs: MockSqs, api: MockApi, state: Arc<State>) -> Health {
+        Health::new(
+            state,
+            Arc::new(sqs),
+            Arc::new(api),
+            Some(Url::from_str("http://dummy:1234/health").unwrap()),
+            Duration::from_millis(100),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_live() {
+        let state = Arc::new(State::new());
+        let health = health(MockSqs::new(), MockApi::new(), state.clone());
+        assert!(health.live().is_ok());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(health.live().is_err());
+
+        state.tick();
+        assert!(health.live().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let mut sqs = MockSqs::new();
+        sqs.expect_check().times(1).returning(|| Ok(()));
+        let mut api = MockApi::new();
+        api.expect_get().times(1).returning(|_| Ok(()));
+        let state = Arc::new(State::new());
+        let health = health(sqs, api, state.clone());
+        assert!(health.ready().await.is_ok());
+
+        // no more calls to SQS and API while draining
+        state.drain();
+        assert!(health.ready().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_if_sqs_fails() {
+        let mut sqs = MockSqs::new();
+        sqs.expect_check()
+            .times(1)
+            .returning(|| Err(SqsError::Connection("Error".to_string())));
+        // the API is checked concurrently
+        let mut api = MockApi::new();
+        api.expect_get().returning(|_| Ok(()));
+        let health = health(sqs, api, Arc::new(State::new()));
+
+        assert!(health.ready().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_if_api_fails() {
+        let mut sqs = MockSqs::new();
+        sqs.expect_check().times(1).returning(|| Ok(()));
+        let mut api = MockApi::new();
+        api.expect_get()
+            .times(1)
+            .returning(|_| Err(ApiError::Connection("Error".to_string())));
+        let health = health(sqs, api, Arc::new(State::new()));
+
+        assert!(health.ready().await.is_err());
+    }
+
+    /// API whose health check never responds.
+    struct HangingApi;
+
+    #[async_trait]
+    impl Api for HangingApi {
+        async fn get(&self, _url: &Url) -> Result<(), ApiError> {
+            std::future::pending().await
+        }
+
+        async fn post(&self, _message: &Message) -> Result<(bool, String), ApiError> {
+            Err(ApiError::Other(anyhow!("Not used by the health check.")))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_if_api_hangs() {
+        let mut sqs = MockSqs::new();
+        sqs.expect_check().times(1).returning(|| Ok(()));
+        let health = Health {
+            api: Arc::new(HangingApi),
+            check_timeout: Duration::from_millis(100),
+            ..health(sqs, MockApi::new(), Arc::new(State::new()))
+        };
+
+        let result = tokio::time::timeout(Duration::from_secs(1), health.ready()).await;
+        assert!(result.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_checks_share_the_timeout() {
+        let mut sqs = MockSqs::new();
+        sqs.expect_check().times(1).returning(|| {
+            std::thread::sleep(Duration::from_millis(150));
+            Ok(())
+        });
+        let health = Health {
+            api: Arc::new(HangingApi),
+            check_timeout: Duration::from_millis(200),
+            ..health(sqs, MockApi::new(), Arc::new(State::new()))
+        };
+
+        let started = Instant::now();
+        assert!(health.ready().await.is_err());
+        assert!(started.elapsed() < Duration::from_millis(300));
+    }
+}
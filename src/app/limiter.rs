@@ -0,0 +1,347 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+use tracing::info;
+use url::Url;
+
+use crate::domain::config::Config;
+use crate::domain::error::ApiError;
+use crate::domain::message::Message;
+use crate::infra::api::Api;
+use crate::infra::metrics::Metrics;
+use prometheus::IntGauge;
+
+/// AIMD (additive increase, multiplicative decrease) concurrency limiter.
+///
+/// Workers hold a permit from asking for a message until it is processed, so that at most
+/// `limit` messages are received at once. The limit grows by one on each fast successful call
+/// while the limiter is busy, and shrinks by `backoff_ratio` on a congested or slow call.
+/// Calls started before the last decrease saw the higher limit, so they do not shrink it again.
+pub struct Limiter {
+    state: Mutex<State>,
+    limit_gauge: IntGauge,
+    notify: Notify,
+}
+
+struct State {
+    limit: usize,
+    /// Permits held by workers.
+    in_flight: usize,
+    /// API calls in progress.
+    busy: usize,
+    min: usize,
+    max: usize,
+    latency_threshold: Duration,
+    backoff_ratio: f64,
+    decreased_at: Option<Instant>,
+}
+
+pub enum Outcome {
+    Success(Duration),
+    /// The API is congested: a timeout, a connection error, throttling or a server error.
+    Dropped,
+    /// The call failed for a reason which tells nothing about the load, e.g. a client error.
+    Ignored,
+}
+
+pub struct Permit<'a> {
+    limiter: &'a Limiter,
+}
+
+/// API call whose outcome is fed back to the limiter.
+pub struct Call<'a> {
+    limiter: &'a Limiter,
+    started: Instant,
+}
+
+impl Limiter {
+    pub fn new(config: &Config) -> Self {
+        let limit = config
+            .num_workers
+            .clamp(config.min_concurrency, config.max_concurrency);
+        let limit_gauge = Metrics::new(config.sqs_url.as_str()).concurrency_limit;
+        limit_gauge.set(limit as i64);
+        Limiter {
+            state: Mutex::new(State {
+                limit,
+                in_flight: 0,
+                busy: 0,
+                min: config.min_concurrency,
+                max: config.max_concurrency,
+                latency_threshold: Duration::from_millis(config.concurrency_latency_threshold_msec),
+                backoff_ratio: config.concurrency_backoff_ratio,
+                decreased_at: None,
+            }),
+            limit_gauge,
+            notify: Notify::new(),
+        }
+    }
+
+    /// Applies new bounds and parameters, keeping the current limit within the bounds.
+    pub fn reconfigure(&self, config: &Config) {
+        let mut state = self.state.lock().unwrap();
+        state.min = config.min_concurrency;
+        state.max = config.max_concurrency;
+        state.latency_threshold = Duration::from_millis(config.concurrency_latency_threshold_msec);
+        state.backoff_ratio = config.concurrency_backoff_ratio;
+        state.limit = state.limit.clamp(state.min, state.max);
+        self.limit_gauge.set(state.limit as i64);
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    pub async fn acquire(&self) -> Permit<'_> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    return Permit { limiter: self };
+                }
+            }
+            notified.await;
+        }
+    }
+
+    pub fn call(&self) -> Call<'_> {
+        self.state.lock().unwrap().busy += 1;
+        Call {
+            limiter: self,
+            started: Instant::now(),
+        }
+    }
+
+    fn on_sample(&self, started: Instant, outcome: Outcome) {
+        let mut state = self.state.lock().unwrap();
+        let old = state.limit;
+        match outcome {
+            Outcome::Success(latency) if latency <= state.latency_threshold => {
+                // Only grow while the current limit is actually used.
+                if state.busy * 2 >= state.limit {
+                    state.limit = (state.limit + 1).min(state.max);
+                }
+            }
+            Outcome::Success(_) | Outcome::Dropped => {
+                if state.decreased_at.is_none_or(|at| started >= at) {
+                    state.limit =
+                        ((state.limit as f64 * state.backoff_ratio) as usize).max(state.min);
+                    state.decreased_at = Some(Instant::now());
+                }
+            }
+            Outcome::Ignored => {}
+        }
+        state.busy -= 1;
+
+        if state.limit != old {
+            self.limit_gauge.set(state.limit as i64);
+            info!("Concurrency limit changed. ({} -> {})", old, state.limit);
+        }
+        drop(state);
+        self.notify.notify_waiters();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.notify.notify_waiters();
+    }
+}
+
+impl Call<'_> {
+    pub fn finish(self, outcome: Outcome) {
+        self.limiter.on_sample(self.started, outcome);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Call<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().busy -= 1;
+    }
+}
+
+/// `Api` decorator which feeds the latency and the result of every POST request back to the
+/// limiter. Permits are acquired by the workers before they receive messages.
+pub struct LimitedApi {
+    api: Arc<dyn Api + Send + Sync>,
+    limiter: Arc<Limiter>,
+}
+
+impl LimitedApi {
+    pub fn new(api: Arc<dyn Api + Send + Sync>, limiter: Arc<Limiter>) -> Self {
+        LimitedApi { api, limiter }
+    }
+}
+
+#[async_trait]
+impl Api for LimitedApi {
+    async fn get(&self, url: &Url) -> Result<(), ApiError> {
+        self.api.get(url).await
+    }
+
+    async fn post(&self, message: &Message) -> Result<(bool, String), ApiError> {
+        let call = self.limiter.call();
+        let result = self.api.post(message).await;
+        let outcome = match &result {
+            Ok((true, _)) => Outcome::Success(call.started.elapsed()),
+            Err(
+                ApiError::Timeout(_)
+                | ApiError::Connection(_)
+                | ApiError::Throttled(_)
+                | ApiError::ServerError(_),
+            ) => Outcome::Dropped,
+            _ => Outcome::Ignored,
+        };
+        call.finish(outcome);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::config::ConcurrencyMode;
+    use crate::infra::api::MockApi;
+    use structopt::StructOpt;
+
+    fn limiter(num_workers: usize) -> Limiter {
+        Limiter::new(&config(num_workers))
+    }
+
+    fn config(num_workers: usize) -> Config {
+        let mut config = Config::from_iter(&[
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-url",
+            "http://localhost:4000/",
+        ]);
+        config.num_workers = num_workers;
+        config.concurrency_mode = ConcurrencyMode::Adaptive;
+        config.min_concurrency = 2;
+        config.max_concurrency = 4;
+        config.concurrency_latency_threshold_msec = 100;
+        config.concurrency_backoff_ratio = 0.5;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_limit_increases_on_fast_success() {
+        let limiter = limiter(3);
+        assert_eq!(limiter.limit(), 3);
+
+        for _ in 0..3 {
+            let call = limiter.call();
+            call.finish(Outcome::Success(Duration::from_millis(10)));
+        }
+        // grows only while busy: 1 call of limit 3 is not enough
+        assert_eq!(limiter.limit(), 3);
+
+        let calls = vec![limiter.call(), limiter.call()];
+        for call in calls {
+            call.finish(Outcome::Success(Duration::from_millis(10)));
+        }
+        assert_eq!(limiter.limit(), 4);
+
+        // bounded by max
+        let calls = vec![limiter.call(), limiter.call(), limiter.call()];
+        for call in calls {
+            call.finish(Outcome::Success(Duration::from_millis(10)));
+        }
+        assert_eq!(limiter.limit(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_limit_decreases_on_drop_or_slow_response() {
+        let limiter = limiter(4);
+
+        limiter.call().finish(Outcome::Dropped);
+        assert_eq!(limiter.limit(), 2);
+
+        // bounded by min
+        limiter
+            .call()
+            .finish(Outcome::Success(Duration::from_millis(500)));
+        assert_eq!(limiter.limit(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_limit_decreases_once_per_window() {
+        let mut config = config(4);
+        config.min_concurrency = 1;
+        config.concurrency_backoff_ratio = 0.75;
+        let limiter = Limiter::new(&config);
+
+        // calls in flight at the first decrease do not decrease the limit again
+        let calls = vec![limiter.call(), limiter.call(), limiter.call()];
+        for call in calls {
+            call.finish(Outcome::Dropped);
+        }
+        assert_eq!(limiter.limit(), 3);
+
+        limiter.call().finish(Outcome::Dropped);
+        assert_eq!(limiter.limit(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_limited_api_outcomes() {
+        let cases = vec![
+            (Ok((true, String::new())), 4),
+            (Ok((false, String::new())), 4),
+            (Err(ApiError::InvalidMessage("invalid".to_string())), 4),
+            (Err(ApiError::Other(anyhow::anyhow!("other"))), 4),
+            (Err(ApiError::Timeout("timeout".to_string())), 2),
+            (Err(ApiError::Connection("refused".to_string())), 2),
+            (Err(ApiError::Throttled("429".to_string())), 2),
+            (Err(ApiError::ServerError("503".to_string())), 2),
+        ];
+        let message = Message {
+            body: "body".to_string(),
+            receipt_handle: "receipt_handle".to_string(),
+            md5_of_body: "841a2d689ad86bd1611447453c22c6fc".to_string(),
+            message_id: "message_id".to_string(),
+            attributes: Default::default(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
+        };
+        for (result, limit) in cases {
+            let description = format!("{:?}", result);
+            let mut api = MockApi::new();
+            api.expect_post().return_once(move |_| result);
+            let limiter = Arc::new(limiter(4));
+            let api = LimitedApi::new(Arc::new(api), limiter.clone());
+
+            let _ = api.post(&message).await;
+            assert_eq!(limiter.limit(), limit, "{}", description);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_release() {
+        let limiter = limiter(2);
+        let first = limiter.acquire().await;
+        let _second = limiter.acquire().await;
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), limiter.acquire())
+                .await
+                .is_err()
+        );
+
+        drop(first);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), limiter.acquire())
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use async_trait::async_trait;
+use std::sync::{Arc, RwLock};
+use url::Url;
+
+use crate::domain::error::ApiError;
+use crate::domain::message::Message;
+use crate::infra::api::Api;
+
+/// `Api` decorator whose implementation can be replaced at runtime.
+/// Requests already in flight keep using the previous implementation.
+pub struct ReloadableApi {
+    api: RwLock<Arc<dyn Api + Send + Sync>>,
+}
+
+impl ReloadableApi {
+    pub fn new(api: Arc<dyn Api + Send + Sync>) -> Self {
+        ReloadableApi {
+            api: RwLock::new(api),
+        }
+    }
+
+    pub fn replace(&self, api: Arc<dyn Api + Send + Sync>) {
+        *self.api.write().unwrap() = api;
+    }
+
+    fn current(&self) -> Arc<dyn Api + Send + Sync> {
+        self.api.read().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Api for ReloadableApi {
+    async fn get(&self, url: &Url) -> Result<(), ApiError> {
+        self.current().get(url).await
+    }
+
+    async fn post(&self, message: &Message) -> Result<(bool, String), ApiError> {
+        self.current().post(message).await
+    }
+}
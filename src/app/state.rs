@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+/// Runtime state of the daemon shared with the admin endpoints and signal handlers.
+pub struct State {
+    last_tick: Mutex<Instant>,
+    draining: AtomicBool,
+    paused: AtomicBool,
+    resumed: Notify,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    pub fn new() -> Self {
+        State {
+            last_tick: Mutex::new(Instant::now()),
+            draining: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            resumed: Notify::new(),
+        }
+    }
+
+    /// Records that the receive loop is alive.
+    pub fn tick(&self) {
+        *self.last_tick.lock().unwrap() = Instant::now();
+    }
+
+    pub fn since_last_tick(&self) -> Duration {
+        self.last_tick.lock().unwrap().elapsed()
+    }
+
+    /// Marks that the daemon stops receiving new messages and waits for in-flight ones.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Stops receiving new messages. Returns `false` if already paused.
+    pub fn pause(&self) -> bool {
+        !self.paused.swap(true, Ordering::SeqCst)
+    }
+
+    /// Restarts receiving messages. Returns `false` if not paused.
+    pub fn resume(&self) -> bool {
+        let changed = self.paused.swap(false, Ordering::SeqCst);
+        self.resumed.notify_waiters();
+        changed
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub async fn wait_resumed(&self) {
+        let resumed = self.resumed.notified();
+        if self.is_paused() {
+            resumed.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        let state = Arc::new(State::new());
+        assert!(!state.is_paused());
+        assert!(state.pause());
+        assert!(!state.pause());
+
+        let waiting = {
+            let state = state.clone();
+            tokio::spawn(async move { state.wait_resumed().await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(state.resume());
+        assert!(!state.resume());
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,2 +1,5 @@
 pub mod config;
+pub mod error;
 pub mod message;
+pub mod template;
+pub mod transform;
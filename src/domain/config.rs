@@ -1,11 +1,183 @@
-use anyhow::{anyhow, Result};
+use crate::domain::template::RequestTemplate;
+use crate::domain::transform::{JsonPath, OutputMerge, OutputTemplate, ResponseTransform};
+use anyhow::{anyhow, Error, Result};
+use http::header::HeaderName;
 use http::Uri;
+use std::env;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 use url::Url;
 
+mod file;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConcurrencyMode {
+    Fixed,
+    Adaptive,
+}
+
+impl FromStr for ConcurrencyMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fixed" => Ok(ConcurrencyMode::Fixed),
+            "adaptive" => Ok(ConcurrencyMode::Adaptive),
+            _ => Err(anyhow!("Unknown concurrency mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlsVersion {
+    Tls1_0,
+    Tls1_1,
+    Tls1_2,
+    Tls1_3,
+}
+
+impl FromStr for TlsVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "1.0" => Ok(TlsVersion::Tls1_0),
+            "1.1" => Ok(TlsVersion::Tls1_1),
+            "1.2" => Ok(TlsVersion::Tls1_2),
+            "1.3" => Ok(TlsVersion::Tls1_3),
+            _ => Err(anyhow!("Unknown TLS version: {}", s)),
+        }
+    }
+}
+
+/// How a command of `--api-exec-command` is stopped when it times out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KillPolicy {
+    /// SIGTERM, then SIGKILL after `--api-exec-kill-grace-msec`
+    Term,
+    /// SIGKILL immediately
+    Kill,
+}
+
+impl FromStr for KillPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "term" => Ok(KillPolicy::Term),
+            "kill" => Ok(KillPolicy::Kill),
+            _ => Err(anyhow!("Unknown kill policy: {}", s)),
+        }
+    }
+}
+
+/// What to do with a message which cannot be turned into a request, or whose API response cannot
+/// be transformed for the output queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidMessagePolicy {
+    /// Keep the message, so that it is retried and eventually moved to the dead-letter queue
+    Retry,
+    /// Delete the message
+    Delete,
+}
+
+impl FromStr for InvalidMessagePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "retry" => Ok(InvalidMessagePolicy::Retry),
+            "delete" => Ok(InvalidMessagePolicy::Delete),
+            _ => Err(anyhow!("Unknown invalid message policy: {}", s)),
+        }
+    }
+}
+
+/// Body of API requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequestFormat {
+    /// The message body as is
+    Raw,
+    /// JSON object of the message metadata and body
+    Envelope,
+}
+
+impl FromStr for RequestFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "raw" => Ok(RequestFormat::Raw),
+            "envelope" => Ok(RequestFormat::Envelope),
+            _ => Err(anyhow!("Unknown request format: {}", s)),
+        }
+    }
+}
+
+/// Where the value of a custom API request header comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeaderSource {
+    Literal(String),
+    /// Environment variable name, read when the API client is built.
+    Env(String),
+    /// File path, read again whenever the file is modified.
+    File(PathBuf),
+}
+
+/// Custom API request header given as `Name:Value`, `Name:env:VARIABLE` or `Name:file:/path`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiHeader {
+    pub name: HeaderName,
+    pub source: HeaderSource,
+}
+
+impl FromStr for ApiHeader {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("API header should be `Name:Value`: {}", s))?;
+        let name = HeaderName::from_str(name.trim())?;
+        let value = value.trim_start();
+        let source = if let Some(variable) = value.strip_prefix("env:") {
+            HeaderSource::Env(variable.to_string())
+        } else if let Some(path) = value.strip_prefix("file:") {
+            HeaderSource::File(PathBuf::from(path))
+        } else {
+            HeaderSource::Literal(value.to_string())
+        };
+        Ok(ApiHeader { name, source })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, StructOpt)]
 #[structopt(name = "sqsproxyd")]
 pub struct Config {
+    /// TOML or YAML file with the same keys as the field names
+    #[structopt(long, env = "SQSPROXYD_CONFIG")]
+    pub config: Option<PathBuf>,
     #[structopt(long, env = "AWS_ACCESS_KEY_ID")]
     pub aws_access_key_id: Option<String>,
     #[structopt(long, env = "AWS_SECRET_ACCESS_KEY")]
@@ -18,14 +190,142 @@ pub struct Config {
     pub aws_endpoint: Option<Uri>,
     #[structopt(long, env = "SQSPROXYD_SQS_URL")]
     pub sqs_url: Url,
+    /// Either this or `--api-exec-command` is required
     #[structopt(long, env = "SQSPROXYD_API_URL")]
-    pub api_url: Url,
+    pub api_url: Option<Url>,
     #[structopt(long, env = "SQSPROXYD_OUTPUT_SQS_URL")]
     pub output_sqs_url: Option<Url>,
+    /// JSON path of the API response to forward to the output queue, e.g. `result.items`
+    #[structopt(long, env = "SQSPROXYD_OUTPUT_EXTRACT")]
+    pub output_extract: Option<JsonPath>,
+    /// Fields of the input message to add to the forwarded response, e.g. `order_id=body.order_id,source_id=message_id`
+    #[structopt(long, env = "SQSPROXYD_OUTPUT_MERGE")]
+    pub output_merge: Option<OutputMerge>,
+    /// Template of the output message body, e.g. `{"order": {body.order_id}, "result": {response}}`
+    #[structopt(long, env = "SQSPROXYD_OUTPUT_TEMPLATE")]
+    pub output_template: Option<OutputTemplate>,
     #[structopt(long, env = "SQSPROXYD_NUM_WORKERS", default_value = "1")]
     pub num_workers: usize,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_CONCURRENCY_MODE",
+        default_value = "fixed",
+        possible_values = &["fixed", "adaptive"]
+    )]
+    pub concurrency_mode: ConcurrencyMode,
+    #[structopt(long, env = "SQSPROXYD_MIN_CONCURRENCY", default_value = "1")]
+    pub min_concurrency: usize,
+    #[structopt(long, env = "SQSPROXYD_MAX_CONCURRENCY", default_value = "100")]
+    pub max_concurrency: usize,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_CONCURRENCY_LATENCY_THRESHOLD_MSEC",
+        default_value = "1000"
+    )]
+    pub concurrency_latency_threshold_msec: u64,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_CONCURRENCY_BACKOFF_RATIO",
+        default_value = "0.9"
+    )]
+    pub concurrency_backoff_ratio: f64,
     #[structopt(long, env = "SQSPROXYD_API_TIMEOUT_MSEC", default_value = "30000")]
     pub api_timeout_msec: u64,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_API_CONNECT_TIMEOUT_MSEC",
+        default_value = "10000"
+    )]
+    pub api_connect_timeout_msec: u64,
+    #[structopt(long, env = "SQSPROXYD_API_READ_TIMEOUT_MSEC")]
+    pub api_read_timeout_msec: Option<u64>,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_API_TIMEOUT_ATTRIBUTE",
+        default_value = "SqsproxydApiTimeoutMsec"
+    )]
+    pub api_timeout_attribute: String,
+    #[structopt(long, env = "SQSPROXYD_API_MAX_TIMEOUT_MSEC", default_value = "300000")]
+    pub api_max_timeout_msec: u64,
+    #[structopt(long, env = "SQSPROXYD_API_POOL_MAX_IDLE_PER_HOST")]
+    pub api_pool_max_idle_per_host: Option<usize>,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_API_POOL_IDLE_TIMEOUT_SECONDS",
+        default_value = "90"
+    )]
+    pub api_pool_idle_timeout_seconds: u64,
+    #[structopt(long, env = "SQSPROXYD_API_TCP_KEEPALIVE_SECONDS")]
+    pub api_tcp_keepalive_seconds: Option<u64>,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_API_HTTP2_PRIOR_KNOWLEDGE",
+        default_value = "false",
+        parse(try_from_str)
+    )]
+    pub api_http2_prior_knowledge: bool,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_API_HEADER",
+        number_of_values = 1,
+        value_delimiter = "\n"
+    )]
+    pub api_header: Vec<ApiHeader>,
+    /// Token endpoint of the OAuth2 client credentials grant. If set, API requests carry a bearer token.
+    #[structopt(long, env = "SQSPROXYD_API_OAUTH2_TOKEN_URL")]
+    pub api_oauth2_token_url: Option<Url>,
+    #[structopt(long, env = "SQSPROXYD_API_OAUTH2_CLIENT_ID")]
+    pub api_oauth2_client_id: Option<String>,
+    #[structopt(long, env = "SQSPROXYD_API_OAUTH2_CLIENT_SECRET")]
+    pub api_oauth2_client_secret: Option<String>,
+    #[structopt(long, env = "SQSPROXYD_API_OAUTH2_SCOPE")]
+    pub api_oauth2_scope: Option<String>,
+    /// File of the key to sign API requests with HMAC-SHA256
+    #[structopt(long, env = "SQSPROXYD_API_SIGNING_KEY_FILE")]
+    pub api_signing_key_file: Option<PathBuf>,
+    /// PEM file of the client certificate (chain) for mTLS
+    #[structopt(long, env = "SQSPROXYD_API_TLS_CLIENT_CERT")]
+    pub api_tls_client_cert: Option<PathBuf>,
+    /// PEM file of the private key of `--api-tls-client-cert`
+    #[structopt(long, env = "SQSPROXYD_API_TLS_CLIENT_KEY")]
+    pub api_tls_client_key: Option<PathBuf>,
+    /// PEM file of root CA certificates to trust in addition to the system ones
+    #[structopt(long, env = "SQSPROXYD_API_TLS_CA_CERT")]
+    pub api_tls_ca_cert: Option<PathBuf>,
+    /// `1.0`, `1.1`, `1.2` or `1.3`
+    #[structopt(long, env = "SQSPROXYD_API_TLS_MIN_VERSION")]
+    pub api_tls_min_version: Option<TlsVersion>,
+    /// AWS service name to sign API requests with SigV4 (e.g. `execute-api`, `lambda`)
+    #[structopt(long, env = "SQSPROXYD_API_SIGV4_SERVICE")]
+    pub api_sigv4_service: Option<String>,
+    /// AWS region to sign API requests with SigV4. Defaults to the region of SQS.
+    #[structopt(long, env = "SQSPROXYD_API_SIGV4_REGION")]
+    pub api_sigv4_region: Option<String>,
+    /// Method and path of API requests, e.g. `PUT /orders/{body.order_id}?type={attr.kind}`
+    #[structopt(long, env = "SQSPROXYD_API_REQUEST_TEMPLATE")]
+    pub api_request_template: Option<RequestTemplate>,
+    /// `retry` or `delete`
+    #[structopt(
+        long,
+        env = "SQSPROXYD_INVALID_MESSAGE_POLICY",
+        default_value = "retry"
+    )]
+    pub invalid_message_policy: InvalidMessagePolicy,
+    /// gRPC method `/package.Service/Method` to call at `--api-url` instead of POST
+    #[structopt(long, env = "SQSPROXYD_API_GRPC_METHOD")]
+    pub api_grpc_method: Option<String>,
+    /// Command run by `sh -c` for each message instead of calling the API
+    #[structopt(long, env = "SQSPROXYD_API_EXEC_COMMAND")]
+    pub api_exec_command: Option<String>,
+    /// `term` or `kill`
+    #[structopt(long, env = "SQSPROXYD_API_EXEC_KILL_POLICY", default_value = "term")]
+    pub api_exec_kill_policy: KillPolicy,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_API_EXEC_KILL_GRACE_MSEC",
+        default_value = "5000"
+    )]
+    pub api_exec_kill_grace_msec: u64,
     #[structopt(long, env = "SQSPROXYD_SLEEP_MSEC", default_value = "1000")]
     pub sleep_msec: u64,
     #[structopt(long, env = "SQSPROXYD_API_HEALTH_URL")]
@@ -36,19 +336,143 @@ pub struct Config {
         default_value = "1"
     )]
     pub api_health_interval_seconds: u64,
+    #[structopt(long, env = "SQSPROXYD_API_HEALTH_TIMEOUT_SECONDS")]
+    pub api_health_timeout_seconds: Option<u64>,
     #[structopt(
         long,
         env = "SQSPROXYD_CONTENT_TYPE",
         default_value = "application/json"
     )]
     pub content_type: String,
+    /// `raw` (message body) or `envelope` (JSON of the message metadata and body)
+    #[structopt(long, env = "SQSPROXYD_REQUEST_FORMAT", default_value = "raw")]
+    pub request_format: RequestFormat,
     #[structopt(long, env = "SQSPROXYD_RUST_LOG", default_value = "WARN")]
     pub rust_log: String,
+    #[structopt(
+        long,
+        env = "SQSPROXYD_LOG_FORMAT",
+        default_value = "text",
+        possible_values = &["text", "json"]
+    )]
+    pub log_format: LogFormat,
+    #[structopt(long, env = "SQSPROXYD_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<Url>,
+    #[structopt(long, env = "SQSPROXYD_ADMIN_ADDR")]
+    pub admin_addr: Option<SocketAddr>,
+    #[structopt(long, env = "SQSPROXYD_LIVENESS_TIMEOUT_SECONDS", default_value = "60")]
+    pub liveness_timeout_seconds: u64,
 }
 
 impl Config {
-    pub fn new() -> Self {
-        Self::from_args()
+    /// Parses the command-line arguments, the environment variables and the configuration file.
+    /// `--help` and `--version` are returned as `clap::Error`.
+    pub fn try_new() -> Result<Self> {
+        Self::try_from_args(env::args_os())
+    }
+
+    /// `try_new` with the given command-line arguments.
+    fn try_from_args<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let args = file::merge_args(args, |name| env::var(name).ok())?;
+        let matches = Self::clap().get_matches_from_safe(args)?;
+        let mut config = Self::from_clap(&matches);
+        // clap 2 appends the values of the environment variable to the command-line arguments
+        let occurrences = matches.occurrences_of("api_header") as usize;
+        if occurrences > 0 {
+            config.api_header.truncate(occurrences);
+        }
+        Ok(config)
+    }
+
+    /// Merges `new` into the fields which can be changed at runtime.
+    /// Returns the merged configuration and the names of changed fields which require restart.
+    pub fn reload(&self, new: Config) -> (Config, Vec<&'static str>) {
+        let mut merged = new;
+        let mut ignored = vec![];
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if merged.$field != self.$field {
+                        ignored.push(stringify!($field));
+                        merged.$field = self.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep!(
+            aws_access_key_id,
+            aws_secret_access_key,
+            aws_session_token,
+            aws_region,
+            aws_endpoint,
+            sqs_url,
+            output_sqs_url,
+            output_extract,
+            output_merge,
+            output_template,
+            concurrency_mode,
+            api_health_url,
+            rust_log,
+            log_format,
+            otlp_endpoint,
+            admin_addr,
+            liveness_timeout_seconds,
+            invalid_message_policy
+        );
+        (merged, ignored)
+    }
+
+    /// Number of worker tasks to spawn.
+    /// In adaptive mode, the limiter decides how many of them may call the API at once.
+    pub fn worker_count(&self) -> usize {
+        match self.concurrency_mode {
+            ConcurrencyMode::Fixed => self.num_workers,
+            ConcurrencyMode::Adaptive => self.max_concurrency,
+        }
+    }
+
+    /// Transformation of API responses before they are sent to the output queue.
+    pub fn response_transform(&self) -> ResponseTransform {
+        ResponseTransform {
+            extract: self.output_extract.clone(),
+            merge: self.output_merge.clone(),
+            template: self.output_template.clone(),
+        }
+    }
+
+    /// Options which are set and apply to HTTP requests only.
+    fn http_request_options(&self) -> Vec<&'static str> {
+        [
+            ("--api-header", !self.api_header.is_empty()),
+            (
+                "--api-request-template",
+                self.api_request_template.is_some(),
+            ),
+            (
+                "--request-format",
+                self.request_format != RequestFormat::Raw,
+            ),
+            (
+                "--api-oauth2-token-url",
+                self.api_oauth2_token_url.is_some(),
+            ),
+            (
+                "--api-signing-key-file",
+                self.api_signing_key_file.is_some(),
+            ),
+            ("--api-sigv4-service", self.api_sigv4_service.is_some()),
+            ("--api-tls-client-cert", self.api_tls_client_cert.is_some()),
+            ("--api-tls-ca-cert", self.api_tls_ca_cert.is_some()),
+            ("--api-tls-min-version", self.api_tls_min_version.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(option, _)| option)
+        .collect()
     }
 
     pub fn validate(&self) -> Result<()> {
@@ -58,6 +482,89 @@ impl Config {
             return Err(anyhow!("If `--aws-endpoint` is set, `--aws-access-key-id` and `--aws-secret-access-key` should be set."));
         }
 
+        if self.api_oauth2_token_url.is_some()
+            && (self.api_oauth2_client_id.is_none() || self.api_oauth2_client_secret.is_none())
+        {
+            return Err(anyhow!("If `--api-oauth2-token-url` is set, `--api-oauth2-client-id` and `--api-oauth2-client-secret` should be set."));
+        }
+
+        if self.api_tls_client_cert.is_some() != self.api_tls_client_key.is_some() {
+            return Err(anyhow!(
+                "`--api-tls-client-cert` and `--api-tls-client-key` should be set together."
+            ));
+        }
+
+        if self.api_oauth2_token_url.is_some() && self.api_sigv4_service.is_some() {
+            return Err(anyhow!(
+                "`--api-oauth2-token-url` and `--api-sigv4-service` cannot be set together."
+            ));
+        }
+
+        if self.output_sqs_url.is_none()
+            && self.response_transform() != ResponseTransform::default()
+        {
+            return Err(anyhow!(
+                "`--output-extract`, `--output-merge` and `--output-template` require `--output-sqs-url`."
+            ));
+        }
+
+        if self.api_url.is_some() == self.api_exec_command.is_some() {
+            return Err(anyhow!(
+                "Either `--api-url` or `--api-exec-command` should be set."
+            ));
+        }
+
+        if self.api_exec_command.is_some() {
+            if let Some(option) = self.http_request_options().into_iter().next() {
+                return Err(anyhow!(
+                    "`{}` cannot be used with `--api-exec-command`.",
+                    option
+                ));
+            }
+        }
+
+        if let Some(method) = &self.api_grpc_method {
+            if self.api_exec_command.is_some() {
+                return Err(anyhow!(
+                    "`--api-grpc-method` and `--api-exec-command` cannot be set together."
+                ));
+            }
+            if !method.starts_with('/') || method.parse::<http::uri::PathAndQuery>().is_err() {
+                return Err(anyhow!(
+                    "`--api-grpc-method` should be `/package.Service/Method`."
+                ));
+            }
+            // the gRPC client is built without TLS
+            if self
+                .api_url
+                .iter()
+                .chain(&self.api_health_url)
+                .any(|url| url.scheme() != "http")
+            {
+                return Err(anyhow!(
+                    "With `--api-grpc-method`, `--api-url` and `--api-health-url` should be `http://` URLs."
+                ));
+            }
+            if let Some(option) = self.http_request_options().into_iter().next() {
+                return Err(anyhow!(
+                    "`{}` cannot be used with `--api-grpc-method`.",
+                    option
+                ));
+            }
+        }
+
+        if self.min_concurrency == 0 || self.min_concurrency > self.max_concurrency {
+            return Err(anyhow!(
+                "`--min-concurrency` should be between 1 and `--max-concurrency`."
+            ));
+        }
+
+        if self.concurrency_backoff_ratio <= 0.0 || self.concurrency_backoff_ratio >= 1.0 {
+            return Err(anyhow!(
+                "`--concurrency-backoff-ratio` should be greater than 0 and less than 1."
+            ));
+        }
+
         Ok(())
     }
 }
@@ -65,46 +572,116 @@ impl Config {
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::env;
-    use std::str::FromStr;
-
-    fn set_env_vars() {
-        env::set_var("AWS_ACCESS_KEY_ID", "AWSACCESSKEY");
-        env::set_var("AWS_SECRET_ACCESS_KEY", "AWSSECRETACCESSKEY");
-        env::set_var("AWS_SESSION_TOKEN", "AWSSESSIONTOKEN");
-        env::set_var("SQSPROXYD_AWS_REGION", "us-west-1");
-        env::set_var("SQSPROXYD_AWS_ENDPOINT", "http://aws-endpoint.env:2222/");
-        env::set_var(
+    use std::process::Command;
+
+    /// Environment variables of `config_default_is_env`. They are set only in a child process,
+    /// so that they do not leak into the other tests running in parallel.
+    pub(super) const ENV_VARS: &[(&str, &str)] = &[
+        ("AWS_ACCESS_KEY_ID", "AWSACCESSKEY"),
+        ("AWS_SECRET_ACCESS_KEY", "AWSSECRETACCESSKEY"),
+        ("AWS_SESSION_TOKEN", "AWSSESSIONTOKEN"),
+        ("SQSPROXYD_AWS_REGION", "us-west-1"),
+        ("SQSPROXYD_AWS_ENDPOINT", "http://aws-endpoint.env:2222/"),
+        (
             "SQSPROXYD_SQS_URL",
             "https://sqs.us-west-1.amazonaws.com/999999999999/env-sqs-url",
-        );
-        env::set_var("SQSPROXYD_API_URL", "http://api-url.env:5000/");
-        env::set_var(
+        ),
+        ("SQSPROXYD_API_URL", "http://api-url.env:5000/"),
+        (
             "SQSPROXYD_OUTPUT_SQS_URL",
             "https://sqs.us-west-1.amazonaws.com/999999999999/env-output-sqs-url",
-        );
-        env::set_var("SQSPROXYD_NUM_WORKERS", "2");
-        env::set_var("SQSPROXYD_API_TIMEOUT_MSEC", "2");
-        env::set_var("SQSPROXYD_SLEEP_MSEC", "2");
-        env::set_var(
+        ),
+        ("SQSPROXYD_OUTPUT_EXTRACT", "result"),
+        ("SQSPROXYD_OUTPUT_MERGE", "order_id=body.order_id"),
+        ("SQSPROXYD_OUTPUT_TEMPLATE", r#"{"result": {response}}"#),
+        ("SQSPROXYD_NUM_WORKERS", "2"),
+        ("SQSPROXYD_CONCURRENCY_MODE", "adaptive"),
+        ("SQSPROXYD_MIN_CONCURRENCY", "2"),
+        ("SQSPROXYD_MAX_CONCURRENCY", "20"),
+        ("SQSPROXYD_CONCURRENCY_LATENCY_THRESHOLD_MSEC", "200"),
+        ("SQSPROXYD_CONCURRENCY_BACKOFF_RATIO", "0.5"),
+        ("SQSPROXYD_API_TIMEOUT_MSEC", "2"),
+        ("SQSPROXYD_API_CONNECT_TIMEOUT_MSEC", "2"),
+        ("SQSPROXYD_API_READ_TIMEOUT_MSEC", "2"),
+        ("SQSPROXYD_API_TIMEOUT_ATTRIBUTE", "Timeout"),
+        ("SQSPROXYD_API_MAX_TIMEOUT_MSEC", "2"),
+        ("SQSPROXYD_API_POOL_MAX_IDLE_PER_HOST", "2"),
+        ("SQSPROXYD_API_POOL_IDLE_TIMEOUT_SECONDS", "2"),
+        ("SQSPROXYD_API_TCP_KEEPALIVE_SECONDS", "2"),
+        ("SQSPROXYD_API_HTTP2_PRIOR_KNOWLEDGE", "true"),
+        (
+            "SQSPROXYD_API_HEADER",
+            "Authorization:file:/run/secrets/api-token\nX-Tenant:tenant",
+        ),
+        (
+            "SQSPROXYD_API_OAUTH2_TOKEN_URL",
+            "http://oauth2-token-url.env:6000/token",
+        ),
+        ("SQSPROXYD_API_OAUTH2_CLIENT_ID", "client-id"),
+        ("SQSPROXYD_API_OAUTH2_CLIENT_SECRET", "client-secret"),
+        ("SQSPROXYD_API_OAUTH2_SCOPE", "scope"),
+        ("SQSPROXYD_API_SIGNING_KEY_FILE", "/run/secrets/signing-key"),
+        ("SQSPROXYD_API_TLS_CLIENT_CERT", "/run/secrets/client.pem"),
+        ("SQSPROXYD_API_TLS_CLIENT_KEY", "/run/secrets/client.key"),
+        ("SQSPROXYD_API_TLS_CA_CERT", "/run/secrets/ca.pem"),
+        ("SQSPROXYD_API_TLS_MIN_VERSION", "1.2"),
+        // SQSPROXYD_API_SIGV4_SERVICE is not set, because it conflicts with OAuth2
+        ("SQSPROXYD_API_SIGV4_REGION", "us-west-2"),
+        (
+            "SQSPROXYD_API_REQUEST_TEMPLATE",
+            "PUT /orders/{body.order_id}?type={attr.kind}",
+        ),
+        ("SQSPROXYD_INVALID_MESSAGE_POLICY", "delete"),
+        // SQSPROXYD_API_EXEC_COMMAND is not set, because it conflicts with the API URL
+        ("SQSPROXYD_API_EXEC_KILL_POLICY", "kill"),
+        ("SQSPROXYD_API_EXEC_KILL_GRACE_MSEC", "2"),
+        ("SQSPROXYD_SLEEP_MSEC", "2"),
+        (
             "SQSPROXYD_API_HEALTH_URL",
             "http://api-health-check-url.env:5000/",
-        );
-        env::set_var("SQSPROXYD_API_HEALTH_INTERVAL_SECONDS", "2");
-        env::set_var("SQSPROXYD_CONTENT_TYPE", "application/json");
-        env::set_var("SQSPROXYD_RUST_LOG", "INFO")
-    }
+        ),
+        ("SQSPROXYD_API_HEALTH_INTERVAL_SECONDS", "2"),
+        ("SQSPROXYD_API_HEALTH_TIMEOUT_SECONDS", "2"),
+        ("SQSPROXYD_CONTENT_TYPE", "application/json"),
+        ("SQSPROXYD_REQUEST_FORMAT", "envelope"),
+        ("SQSPROXYD_RUST_LOG", "INFO"),
+        ("SQSPROXYD_LOG_FORMAT", "json"),
+        ("SQSPROXYD_OTLP_ENDPOINT", "http://otlp-endpoint.env:4317/"),
+        ("SQSPROXYD_ADMIN_ADDR", "0.0.0.0:9090"),
+        ("SQSPROXYD_LIVENESS_TIMEOUT_SECONDS", "2"),
+    ];
+
+    /// Set in the child process which runs `config_default_is_env`.
+    const CHILD_ENV: &str = "SQSPROXYD_TEST_CHILD";
 
     #[test]
     fn config_default_is_env() {
-        set_env_vars();
+        if env::var_os(CHILD_ENV).is_none() {
+            let output = Command::new(env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "domain::config::test::config_default_is_env",
+                    "--test-threads=1",
+                ])
+                .env(CHILD_ENV, "1")
+                .envs(ENV_VARS.iter().copied())
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+            return;
+        }
 
-        let config = Config::new();
+        let config = Config::try_from_args(["sqsproxyd"]).unwrap();
         config.validate().unwrap();
 
         assert_eq!(
             config,
             Config {
+                config: None,
                 aws_access_key_id: Some("AWSACCESSKEY".to_string()),
                 aws_secret_access_key: Some("AWSSECRETACCESSKEY".to_string()),
                 aws_session_token: Some("AWSSESSIONTOKEN".to_string()),
@@ -114,23 +691,169 @@ mod test {
                     "https://sqs.us-west-1.amazonaws.com/999999999999/env-sqs-url"
                 )
                 .unwrap(),
-                api_url: Url::from_str("http://api-url.env:5000/").unwrap(),
+                api_url: Some(Url::from_str("http://api-url.env:5000/").unwrap()),
                 output_sqs_url: Some(
                     Url::from_str(
                         "https://sqs.us-west-1.amazonaws.com/999999999999/env-output-sqs-url"
                     )
                     .unwrap()
                 ),
+                output_extract: Some(JsonPath::from_str("result").unwrap()),
+                output_merge: Some(OutputMerge::from_str("order_id=body.order_id").unwrap()),
+                output_template: Some(
+                    OutputTemplate::from_str(r#"{"result": {response}}"#).unwrap()
+                ),
                 num_workers: 2,
+                concurrency_mode: ConcurrencyMode::Adaptive,
+                min_concurrency: 2,
+                max_concurrency: 20,
+                concurrency_latency_threshold_msec: 200,
+                concurrency_backoff_ratio: 0.5,
                 api_timeout_msec: 2,
+                api_connect_timeout_msec: 2,
+                api_read_timeout_msec: Some(2),
+                api_timeout_attribute: "Timeout".to_string(),
+                api_max_timeout_msec: 2,
+                api_pool_max_idle_per_host: Some(2),
+                api_pool_idle_timeout_seconds: 2,
+                api_tcp_keepalive_seconds: Some(2),
+                api_http2_prior_knowledge: true,
+                api_header: vec![
+                    ApiHeader {
+                        name: HeaderName::from_static("authorization"),
+                        source: HeaderSource::File(PathBuf::from("/run/secrets/api-token")),
+                    },
+                    ApiHeader {
+                        name: HeaderName::from_static("x-tenant"),
+                        source: HeaderSource::Literal("tenant".to_string()),
+                    },
+                ],
+                api_oauth2_token_url: Some(
+                    Url::from_str("http://oauth2-token-url.env:6000/token").unwrap()
+                ),
+                api_oauth2_client_id: Some("client-id".to_string()),
+                api_oauth2_client_secret: Some("client-secret".to_string()),
+                api_oauth2_scope: Some("scope".to_string()),
+                api_signing_key_file: Some(PathBuf::from("/run/secrets/signing-key")),
+                api_tls_client_cert: Some(PathBuf::from("/run/secrets/client.pem")),
+                api_tls_client_key: Some(PathBuf::from("/run/secrets/client.key")),
+                api_tls_ca_cert: Some(PathBuf::from("/run/secrets/ca.pem")),
+                api_tls_min_version: Some(TlsVersion::Tls1_2),
+                api_sigv4_service: None,
+                api_sigv4_region: Some("us-west-2".to_string()),
+                api_request_template: Some(
+                    RequestTemplate::from_str("PUT /orders/{body.order_id}?type={attr.kind}")
+                        .unwrap()
+                ),
+                invalid_message_policy: InvalidMessagePolicy::Delete,
+                // set with HTTP-only options, it fails validation
+                api_grpc_method: None,
+                api_exec_command: None,
+                api_exec_kill_policy: KillPolicy::Kill,
+                api_exec_kill_grace_msec: 2,
                 sleep_msec: 2,
                 api_health_url: Some(
                     Url::from_str("http://api-health-check-url.env:5000/").unwrap()
                 ),
                 api_health_interval_seconds: 2,
+                api_health_timeout_seconds: Some(2),
                 content_type: "application/json".to_string(),
+                request_format: RequestFormat::Envelope,
                 rust_log: "INFO".to_string(),
+                log_format: LogFormat::Json,
+                otlp_endpoint: Some(Url::from_str("http://otlp-endpoint.env:4317/").unwrap()),
+                admin_addr: Some(SocketAddr::from(([0, 0, 0, 0], 9090))),
+                liveness_timeout_seconds: 2,
             }
         )
     }
+    #[test]
+    fn reload_keeps_restart_required_fields() {
+        let current = Config::from_iter(&[
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-url",
+            "http://localhost:4000/",
+        ]);
+        let mut new = current.clone();
+        new.sqs_url = Url::from_str("http://localhost:9324/queue/other").unwrap();
+        new.api_url = Some(Url::from_str("http://localhost:4000/other").unwrap());
+        new.num_workers = current.num_workers + 1;
+
+        let (merged, ignored) = current.reload(new);
+
+        assert_eq!(ignored, vec!["sqs_url"]);
+        assert_eq!(merged.sqs_url, current.sqs_url);
+        assert_eq!(
+            merged.api_url,
+            Some(Url::from_str("http://localhost:4000/other").unwrap())
+        );
+        assert_eq!(merged.num_workers, current.num_workers + 1);
+    }
+
+    #[test]
+    fn grpc_and_exec_reject_http_options() {
+        let with = |mode: &[&str], args: &[&str]| {
+            let mut config = Config::from_iter(
+                ["sqsproxyd", "--sqs-url", "http://localhost:9324/queue/sqs"]
+                    .iter()
+                    .chain(mode)
+                    .chain(args),
+            );
+            // other tests load `env/test.env`, which sets `SQSPROXYD_API_URL`
+            if config.api_exec_command.is_some() {
+                config.api_url = None;
+            }
+            config.validate().map(|_| config)
+        };
+        let grpc = [
+            "--api-url",
+            "http://localhost:50051",
+            "--api-grpc-method",
+            "/sqsproxyd.v1.Worker/Process",
+        ];
+        let exec = ["--api-exec-command", "cat"];
+
+        assert!(with(&grpc, &[]).is_ok());
+        assert!(with(&exec, &[]).is_ok());
+        assert!(with(&grpc, &["--api-health-url", "https://localhost:50051"]).is_err());
+        for mode in [&grpc[..], &exec] {
+            for args in [
+                &["--api-header", "X-Tenant:tenant"][..],
+                &["--api-request-template", "PUT /orders/{message_id}"],
+                &["--request-format", "envelope"],
+                &[
+                    "--api-oauth2-token-url",
+                    "http://localhost:6000/token",
+                    "--api-oauth2-client-id",
+                    "client",
+                    "--api-oauth2-client-secret",
+                    "secret",
+                ],
+                &["--api-signing-key-file", "/run/secrets/signing-key"],
+                &["--api-sigv4-service", "execute-api"],
+                &[
+                    "--api-tls-client-cert",
+                    "/run/secrets/client.pem",
+                    "--api-tls-client-key",
+                    "/run/secrets/client.key",
+                ],
+                &["--api-tls-ca-cert", "/run/secrets/ca.pem"],
+                &["--api-tls-min-version", "1.2"],
+            ] {
+                let e = with(mode, args).unwrap_err();
+                assert!(
+                    e.to_string().contains(args[0]) && e.to_string().contains(mode[mode.len() - 2]),
+                    "{:?} is rejected with: {}",
+                    args,
+                    e
+                );
+            }
+        }
+
+        let mut config = with(&grpc, &[]).unwrap();
+        config.api_url = Some(Url::from_str("https://localhost:50051").unwrap());
+        assert!(config.validate().is_err());
+    }
 }
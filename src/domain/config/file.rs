@@ -0,0 +1,326 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use structopt::clap;
+use structopt::StructOpt;
+
+use super::Config;
+
+const CONFIG_OPTION: &str = "config";
+const CONFIG_ENV: &str = "SQSPROXYD_CONFIG";
+
+/// Appends the values of the configuration file to the command-line arguments,
+/// except for the options which are already set by the arguments or the environment variables.
+/// So the precedence is: command-line arguments > environment variables > file > default.
+pub fn merge_args<I, T, F>(args: I, env: F) -> Result<Vec<OsString>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+    F: Fn(&str) -> Option<String>,
+{
+    let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+
+    let path = match arg_value(&args, CONFIG_OPTION).or_else(|| env(CONFIG_ENV)) {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let values = load(Path::new(&path))
+        .with_context(|| format!("Failed to load configuration file `{}`.", path))?;
+
+    for (key, value) in values {
+        let long = key.replace('_', "-");
+        if !is_option(&long) {
+            return Err(anyhow!(
+                "Unknown key `{}` in configuration file `{}`.",
+                key,
+                path
+            ));
+        }
+
+        if arg_value(&args, &long).is_some()
+            || env_names(&long.replace('-', "_"))
+                .iter()
+                .any(|name| env(name).is_some())
+        {
+            continue;
+        }
+        // an array is given as a repeated option
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = interpolate(&to_string(&key, value)?, &env)?;
+            args.push(format!("--{}", long).into());
+            args.push(value.into());
+        }
+    }
+
+    Ok(args)
+}
+
+/// Reads a TOML (`.toml`) or YAML (`.yaml`, `.yml`) file into a flat map.
+fn load(path: &Path) -> Result<BTreeMap<String, Value>> {
+    let content = fs::read_to_string(path)?;
+    let values = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content)?,
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&content)?,
+        _ => return Err(anyhow!("The extension should be `toml`, `yaml` or `yml`.")),
+    };
+    Ok(values)
+}
+
+fn to_string(key: &str, value: Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(anyhow!(
+            "The value of `{}` should be a string, number or boolean.",
+            key
+        )),
+    }
+}
+
+/// Replaces `${NAME}` with the value of the environment variable `NAME`.
+fn interpolate<F>(value: &str, env: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed `${{` in `{}`.", value))?;
+        let name = &rest[start + 2..start + end];
+        let var =
+            env(name).ok_or_else(|| anyhow!("Environment variable `{}` is not set.", name))?;
+        result.push_str(&rest[..start]);
+        result.push_str(&var);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Returns the value of `--<long> <value>` or `--<long>=<value>` in the arguments.
+fn arg_value(args: &[OsString], long: &str) -> Option<String> {
+    let flag = format!("--{}", long);
+    let mut iter = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = iter.next() {
+        if arg == flag {
+            return iter.next().map(|value| value.into_owned());
+        }
+        if let Some(value) = arg.strip_prefix(&format!("{}=", flag)) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Options of clap itself, which print a message and exit rather than configure the daemon.
+const CLAP_OPTIONS: &[&str] = &["help", "version"];
+
+/// Whether `--<long>` is an option of `Config` which the file can set. clap 2 has no public
+/// list of the options, so this asks its parser with an empty value: a known option fails
+/// on the value at most, while an unknown one fails as an unknown argument.
+fn is_option(long: &str) -> bool {
+    if long == CONFIG_OPTION || CLAP_OPTIONS.contains(&long) {
+        return false;
+    }
+    let mut args = vec!["sqsproxyd".to_string()];
+    // the only required option, so that a missing one does not hide the result
+    if long != "sqs-url" {
+        args.push("--sqs-url=http://localhost/".to_string());
+    }
+    args.push(format!("--{}=", long));
+    match Config::clap().get_matches_from_safe(args) {
+        Ok(_) => true,
+        Err(e) => matches!(
+            e.kind,
+            clap::ErrorKind::EmptyValue
+                | clap::ErrorKind::InvalidValue
+                | clap::ErrorKind::ValueValidation
+        ),
+    }
+}
+
+/// Environment variables which set the option `key`: the standard AWS variables for the
+/// credentials, and `SQSPROXYD_<KEY>` for the others, as declared in `Config`. The region is
+/// also read by the AWS SDK from its variables when `--aws-region` is not set.
+fn env_names(key: &str) -> Vec<String> {
+    match key {
+        "aws_access_key_id" | "aws_secret_access_key" | "aws_session_token" => {
+            vec![key.to_uppercase()]
+        }
+        "aws_region" => vec![
+            "SQSPROXYD_AWS_REGION".to_string(),
+            "AWS_REGION".to_string(),
+            "AWS_DEFAULT_REGION".to_string(),
+        ],
+        _ => vec![format!("SQSPROXYD_{}", key.to_uppercase())],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+
+    /// Configuration file which is removed when dropped.
+    struct TempFile(String);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn write(name: &str, content: &str) -> TempFile {
+        let path = env::temp_dir().join(format!("sqsproxyd-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        TempFile(path.to_string_lossy().into_owned())
+    }
+
+    fn fake_env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn args(args: &[&str], env: &[(&str, &str)]) -> Result<Vec<String>> {
+        Ok(merge_args(args.iter(), fake_env(env))?
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn test_precedence() {
+        let path = write(
+            "precedence.toml",
+            r#"
+sqs_url = "http://localhost:9324/queue/sqs"
+api-url = "http://localhost:4000/"
+num_workers = 3
+api_http2_prior_knowledge = true
+sleep_msec = 500
+api_header = ["Authorization:file:/run/secrets/token", "X-Tenant:tenant"]
+"#,
+        );
+
+        let args = args(
+            &["sqsproxyd", "--config", &path.0, "--num-workers=4"],
+            &[("SQSPROXYD_SLEEP_MSEC", "100")],
+        )
+        .unwrap();
+
+        assert_eq!(
+            args[4..],
+            [
+                "--api-url",
+                "http://localhost:4000/",
+                "--api-header",
+                "Authorization:file:/run/secrets/token",
+                "--api-header",
+                "X-Tenant:tenant",
+                "--api-http2-prior-knowledge",
+                "true",
+                "--sqs-url",
+                "http://localhost:9324/queue/sqs",
+            ]
+        );
+        Config::from_iter_safe(args).unwrap();
+    }
+
+    #[test]
+    fn test_yaml_and_interpolation() {
+        let path = write(
+            "interpolation.yaml",
+            "sqs_url: http://${SQS_HOST}/queue/sqs\napi_url: http://localhost:4000/\n",
+        );
+
+        let args = args(
+            &["sqsproxyd"],
+            &[
+                ("SQSPROXYD_CONFIG", &path.0),
+                ("SQS_HOST", "localhost:9324"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            args[1..],
+            [
+                "--api-url",
+                "http://localhost:4000/",
+                "--sqs-url",
+                "http://localhost:9324/queue/sqs",
+            ]
+        );
+
+        let path = write("missing.yml", "sqs_url: http://${SQS_HOST}/queue/sqs\n");
+        assert!(args_err(&["sqsproxyd", "--config", &path.0]).contains("SQS_HOST"));
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let path = write(
+            "unknown.toml",
+            "sqs_url = \"http://localhost:9324/queue/sqs\"\nfoo = 1\n",
+        );
+        assert!(args_err(&["sqsproxyd", "--config", &path.0]).contains("Unknown key `foo`"));
+
+        let path = write("nested.toml", "config = \"other.toml\"\n");
+        assert!(args_err(&["sqsproxyd", "--config", &path.0]).contains("Unknown key `config`"));
+
+        // clap would print a message and exit instead
+        for key in ["help", "version"] {
+            let path = write("builtin.toml", &format!("{} = \"x\"\n", key));
+            assert!(args_err(&["sqsproxyd", "--config", &path.0])
+                .contains(&format!("Unknown key `{}`", key)));
+        }
+    }
+
+    #[test]
+    fn test_env_beats_file() {
+        let path = write(
+            "env.toml",
+            "sqs_url = \"http://localhost:9324/queue/sqs\"\napi-url = \"http://file:4000/\"\naws_region = \"us-east-1\"\n",
+        );
+
+        let args = args(
+            &["sqsproxyd", "--config", &path.0],
+            &[
+                ("SQSPROXYD_API_URL", "http://env:4000/"),
+                ("AWS_DEFAULT_REGION", "us-west-2"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(args[3..], ["--sqs-url", "http://localhost:9324/queue/sqs"]);
+    }
+
+    #[test]
+    fn test_env_names() {
+        assert_eq!(env_names("aws_access_key_id"), ["AWS_ACCESS_KEY_ID"]);
+        assert!(env_names("aws_region").contains(&"AWS_DEFAULT_REGION".to_string()));
+        for (name, _) in super::super::test::ENV_VARS {
+            let key = name.trim_start_matches("SQSPROXYD_").to_lowercase();
+            assert!(is_option(&key.replace('_', "-")), "{}", key);
+            assert_eq!(env_names(&key)[0], *name);
+        }
+        assert!(!is_option("foo"));
+    }
+
+    fn args_err(argv: &[&str]) -> String {
+        format!("{:#}", args(argv, &[]).unwrap_err())
+    }
+}
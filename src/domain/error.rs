@@ -0,0 +1,89 @@
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors which stop the daemon. Each kind has its own exit code, so that orchestrators
+/// can tell misconfiguration apart from crashes.
+#[derive(Debug, Error)]
+pub enum FatalError {
+    #[error("Invalid configuration: {0:#}")]
+    Config(anyhow::Error),
+    #[error("Failed to authenticate with AWS. ({0})")]
+    AwsAuth(String),
+    #[error("SQS queue is not found. ({0})")]
+    QueueNotFound(String),
+    #[error("API health check did not pass within {0:?}.")]
+    HealthCheckTimeout(Duration),
+    #[error("Unexpected error. ({0:?})")]
+    Other(#[from] anyhow::Error),
+}
+
+impl FatalError {
+    /// Exit code of the process. `0` is a graceful shutdown.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FatalError::Other(_) => 1,
+            FatalError::Config(_) => 2,
+            FatalError::AwsAuth(_) => 3,
+            FatalError::QueueNotFound(_) => 4,
+            FatalError::HealthCheckTimeout(_) => 5,
+        }
+    }
+}
+
+/// Errors of SQS requests, classified by how the daemon should react to them.
+#[derive(Debug, Error)]
+pub enum SqsError {
+    #[error("SQS request was throttled. ({0})")]
+    Throttled(String),
+    #[error("Failed to authenticate with AWS. ({0})")]
+    Auth(String),
+    #[error("SQS queue is not found. ({0})")]
+    QueueNotFound(String),
+    #[error("Failed to connect to SQS. ({0})")]
+    Connection(String),
+    #[error("SQS request timed out. ({0})")]
+    Timeout(String),
+    #[error("SQS request failed. ({0:?})")]
+    Other(#[from] anyhow::Error),
+}
+
+/// Errors of API requests.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    /// The message cannot be turned into a request, so retrying does not help.
+    #[error("Invalid message. ({0})")]
+    InvalidMessage(String),
+    #[error("Failed to connect to the API. ({0})")]
+    Connection(String),
+    #[error("API request timed out. ({0})")]
+    Timeout(String),
+    #[error("API request was throttled. ({0})")]
+    Throttled(String),
+    /// The API rejects the credentials of sqsproxyd (e.g. HTTP 401 or 403).
+    #[error("API request is not authorized. ({0})")]
+    Auth(String),
+    /// 5xx status.
+    #[error("API returns a server error. ({0})")]
+    ServerError(String),
+    #[error("API request failed. ({0:?})")]
+    Other(#[from] anyhow::Error),
+}
+
+/// The API response cannot be transformed for `--output-sqs-url`. The API has already
+/// processed the message, so retrying calls it again.
+#[derive(Debug, Error)]
+#[error("Failed to transform the API response, so it is not forwarded. ({error:#}) (response: {response})")]
+pub struct TransformError {
+    pub error: anyhow::Error,
+    pub response: String,
+}
+
+impl From<SqsError> for FatalError {
+    fn from(e: SqsError) -> Self {
+        match e {
+            SqsError::Auth(message) => FatalError::AwsAuth(message),
+            SqsError::QueueNotFound(url) => FatalError::QueueNotFound(url),
+            e => FatalError::Other(e.into()),
+        }
+    }
+}
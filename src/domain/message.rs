@@ -1,4 +1,7 @@
+use aws_sdk_sqs::model::MessageSystemAttributeName;
 use md5;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Message {
@@ -6,6 +9,24 @@ pub struct Message {
     pub receipt_handle: String,
     pub md5_of_body: String,
     pub message_id: String,
+    /// String and Number message attributes. Binary attributes are not kept.
+    pub attributes: HashMap<String, String>,
+    /// `ApproximateReceiveCount` system attribute.
+    pub receive_count: Option<u32>,
+    /// `SentTimestamp` system attribute (epoch milliseconds).
+    pub sent_timestamp: Option<u64>,
+    /// `AWSTraceHeader` system attribute (X-Ray trace header).
+    pub trace_header: Option<String>,
+}
+
+/// Message sent to the output queue.
+#[derive(Clone, PartialEq, Debug)]
+pub struct OutputMessage {
+    pub body: String,
+    /// String message attributes.
+    pub attributes: HashMap<String, String>,
+    /// Sent as the `AWSTraceHeader` system attribute.
+    pub trace_header: Option<String>,
 }
 
 impl Message {
@@ -13,20 +34,58 @@ impl Message {
         let digest = md5::compute(&self.body);
         format!("{:x}", digest) == self.md5_of_body
     }
+
+    /// Elapsed time since the message was sent to SQS.
+    pub fn age(&self) -> Option<Duration> {
+        let sent = UNIX_EPOCH + Duration::from_millis(self.sent_timestamp?);
+        SystemTime::now().duration_since(sent).ok()
+    }
+
+    /// JSON of the metadata and the body, which is embedded as JSON if it parses.
+    pub fn envelope(&self) -> String {
+        let body = serde_json::from_str::<serde_json::Value>(&self.body)
+            .unwrap_or_else(|_| serde_json::Value::String(self.body.clone()));
+        serde_json::json!({
+            "message_id": self.message_id,
+            // the key name requested for the envelope, which is the SQS `ApproximateReceiveCount`
+            "receipt_count": self.receive_count,
+            "sent_timestamp": self.sent_timestamp,
+            "attributes": self.attributes,
+            "body": body,
+        })
+        .to_string()
+    }
 }
 
 impl From<aws_sdk_sqs::model::Message> for Message {
     fn from(item: aws_sdk_sqs::model::Message) -> Self {
+        let system_attributes = item.attributes.unwrap_or_default();
         Message {
             body: item.body.unwrap(),
             receipt_handle: item.receipt_handle.unwrap(),
             md5_of_body: item.md5_of_body.unwrap(),
             message_id: item.message_id.unwrap(),
+            attributes: item
+                .message_attributes
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(name, value)| value.string_value.map(|v| (name, v)))
+                .collect(),
+            receive_count: system_attributes
+                .get(&MessageSystemAttributeName::ApproximateReceiveCount)
+                .and_then(|v| v.parse().ok()),
+            sent_timestamp: system_attributes
+                .get(&MessageSystemAttributeName::SentTimestamp)
+                .and_then(|v| v.parse().ok()),
+            trace_header: system_attributes
+                .get(&MessageSystemAttributeName::AwsTraceHeader)
+                .cloned(),
         }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -37,6 +96,10 @@ mod tests {
             receipt_handle: "dummy".to_string(),
             md5_of_body: "ea703e7aa1efda0064eaa507d9e8ab7e".to_string(), //  md5 -s 'hoge',
             message_id: "dummy".to_string(),
+            attributes: HashMap::new(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
         };
 
         assert_eq!(true, message.check_hash());
@@ -49,8 +112,45 @@ mod tests {
             receipt_handle: "dummy".to_string(),
             md5_of_body: "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
             message_id: "dummy".to_string(),
+            attributes: HashMap::new(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
         };
 
         assert_eq!(false, message.check_hash());
     }
+
+    #[test]
+    fn test_envelope() {
+        let mut message = Message {
+            body: r#"{"key": 1}"#.to_string(),
+            receipt_handle: "dummy".to_string(),
+            md5_of_body: "dummy".to_string(),
+            message_id: "message_id".to_string(),
+            attributes: [("kind".to_string(), "batch".to_string())]
+                .into_iter()
+                .collect(),
+            receive_count: Some(2),
+            sent_timestamp: None,
+            trace_header: None,
+        };
+        let envelope = |message: &Message| {
+            serde_json::from_str::<serde_json::Value>(&message.envelope()).unwrap()
+        };
+
+        assert_eq!(
+            envelope(&message),
+            serde_json::json!({
+                "message_id": "message_id",
+                "receipt_count": 2,
+                "sent_timestamp": null,
+                "attributes": {"kind": "batch"},
+                "body": {"key": 1},
+            })
+        );
+
+        message.body = "not json".to_string();
+        assert_eq!(envelope(&message)["body"], "not json");
+    }
 }
@@ -0,0 +1,222 @@
+use crate::domain::message::Message;
+use anyhow::{anyhow, Error, Result};
+use http::Method;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::fmt;
+use std::str::FromStr;
+
+/// Characters kept as is in a path segment or a query component (RFC 3986 unreserved).
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Method and URL of API requests, given as `PUT /orders/{body.order_id}?type={attr.kind}`.
+///
+/// Placeholders are `{message_id}`, `{attr.NAME}` (message attribute) and `{body.PATH}`
+/// (field of the JSON body, whose nested keys and array indices are separated by `.`).
+/// Their values are percent-encoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestTemplate {
+    pub method: Method,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// Value of the message referred by a placeholder.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Field {
+    MessageId,
+    Attribute(String),
+    Body(Vec<String>),
+}
+
+impl FromStr for RequestTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (method, target) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Request template should be `METHOD /path`: {}", s))?;
+        let method = Method::from_str(method)?;
+        let target = target.trim_start();
+        if !target.starts_with('/') {
+            return Err(anyhow!(
+                "Request template path should start with `/`: {}",
+                s
+            ));
+        }
+
+        let mut parts = vec![];
+        let mut rest = target;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in request template: {}", s))?
+                + start;
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            parts.push(Part::Field(Field::from_str(&rest[start + 1..end])?));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(RequestTemplate { method, parts })
+    }
+}
+
+impl FromStr for Field {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "message_id" {
+            Ok(Field::MessageId)
+        } else if let Some(name) = s.strip_prefix("attr.") {
+            Ok(Field::Attribute(name.to_string()))
+        } else if s == "body" {
+            Ok(Field::Body(vec![]))
+        } else if let Some(path) = s.strip_prefix("body.") {
+            Ok(Field::Body(path.split('.').map(str::to_string).collect()))
+        } else {
+            Err(anyhow!(
+                "Unknown placeholder in request template: {{{}}}",
+                s
+            ))
+        }
+    }
+}
+
+impl RequestTemplate {
+    /// Path and query (e.g. `/orders/1?type=batch`) for `message`.
+    pub fn render(&self, message: &Message) -> Result<String> {
+        let mut body = None;
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Field(field) => {
+                    let value = match field.value(message, &mut body)? {
+                        serde_json::Value::String(s) => s,
+                        serde_json::Value::Number(n) => n.to_string(),
+                        serde_json::Value::Bool(b) => b.to_string(),
+                        _ => {
+                            return Err(anyhow!("{} should be a string, number or boolean.", field))
+                        }
+                    };
+                    rendered.extend(utf8_percent_encode(&value, COMPONENT));
+                }
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+impl Field {
+    /// Value of the field in `message`. `body` caches the parsed body between calls.
+    pub(crate) fn value(
+        &self,
+        message: &Message,
+        body: &mut Option<serde_json::Value>,
+    ) -> Result<serde_json::Value> {
+        match self {
+            Field::MessageId => Ok(serde_json::Value::String(message.message_id.clone())),
+            Field::Attribute(name) => message
+                .attributes
+                .get(name)
+                .map(|value| serde_json::Value::String(value.clone()))
+                .ok_or_else(|| anyhow!("{} is missing.", self)),
+            Field::Body(path) => {
+                if body.is_none() {
+                    *body = Some(
+                        serde_json::from_str(&message.body)
+                            .map_err(|e| anyhow!("Body is not JSON. ({})", e))?,
+                    );
+                }
+                lookup(body.as_ref().unwrap(), path)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("{} is missing.", self))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::MessageId => write!(f, "Message ID"),
+            Field::Attribute(name) => write!(f, "Message attribute `{}`", name),
+            Field::Body(path) => write!(f, "Body field `{}`", path.join(".")),
+        }
+    }
+}
+
+/// Value at `path` of nested keys and array indices.
+pub(crate) fn lookup<'a>(
+    value: &'a serde_json::Value,
+    path: &[String],
+) -> Option<&'a serde_json::Value> {
+    path.iter().try_fold(value, |value, key| match value {
+        serde_json::Value::Object(object) => object.get(key),
+        serde_json::Value::Array(array) => key.parse::<usize>().ok().and_then(|i| array.get(i)),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn message(body: &str) -> Message {
+        Message {
+            body: body.to_string(),
+            receipt_handle: "receipt_handle".to_string(),
+            md5_of_body: "dummy".to_string(),
+            message_id: "message_id".to_string(),
+            attributes: [("kind".to_string(), "a b/c".to_string())]
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = RequestTemplate::from_str(
+            "PUT /orders/{body.order.id}/items/{body.items.1}?type={attr.kind}&id={message_id}",
+        )
+        .unwrap();
+        assert_eq!(template.method, Method::PUT);
+        assert_eq!(
+            template
+                .render(&message(r#"{"order":{"id":42},"items":["x","y/z"]}"#))
+                .unwrap(),
+            "/orders/42/items/y%2Fz?type=a%20b%2Fc&id=message_id"
+        );
+
+        assert!(template.render(&message("not json")).is_err());
+        assert!(template.render(&message(r#"{"order":{}}"#)).is_err());
+        assert!(template
+            .render(&message(r#"{"order":{"id":{}},"items":["x","y"]}"#))
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(RequestTemplate::from_str("/orders").is_err());
+        assert!(RequestTemplate::from_str("POST orders").is_err());
+        assert!(RequestTemplate::from_str("POST /orders/{body.id").is_err());
+        assert!(RequestTemplate::from_str("POST /orders/{unknown}").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use crate::domain::message::Message;
+use crate::domain::template::{lookup, Field};
+use anyhow::{anyhow, Error, Result};
+use std::str::FromStr;
+
+/// Dot-separated keys and array indices of a JSON value, e.g. `result.items.0`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonPath(Vec<String>);
+
+impl FromStr for JsonPath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(anyhow!("JSON path should not be empty."));
+        }
+        Ok(JsonPath(s.split('.').map(str::to_string).collect()))
+    }
+}
+
+/// Fields of the input message added to the response, given as
+/// `order_id=body.order_id,source_id=message_id`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputMerge(Vec<(String, Field)>);
+
+impl FromStr for OutputMerge {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split(',')
+            .map(|entry| {
+                let (key, field) = entry
+                    .trim()
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Merged field should be `KEY=FIELD`: {}", entry))?;
+                Ok((key.to_string(), Field::from_str(field)?))
+            })
+            .collect::<Result<_>>()
+            .map(OutputMerge)
+    }
+}
+
+/// Output message body with placeholders, e.g. `{"order": {body.order_id}, "result": {response}}`.
+///
+/// Placeholders are those of the request template and `{response}` or `{response.PATH}`.
+/// Their values are inserted as JSON. Braces around anything other than a name, such as
+/// JSON objects, are kept as is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Response(Vec<String>),
+    Field(Field),
+}
+
+impl FromStr for OutputTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let name = rest[1..].find('}').map(|end| &rest[1..end + 1]);
+            match name {
+                Some(name) if is_placeholder(name) => {
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(if name == "response" {
+                        Part::Response(vec![])
+                    } else if let Some(path) = name.strip_prefix("response.") {
+                        Part::Response(path.split('.').map(str::to_string).collect())
+                    } else {
+                        Part::Field(Field::from_str(name)?)
+                    });
+                    rest = &rest[name.len() + 2..];
+                }
+                _ => {
+                    literal.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(OutputTemplate { parts })
+    }
+}
+
+fn is_placeholder(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+}
+
+/// Transformation of the API response into the output message body, applied in the order of
+/// `--output-extract`, `--output-merge` and `--output-template`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResponseTransform {
+    pub extract: Option<JsonPath>,
+    pub merge: Option<OutputMerge>,
+    pub template: Option<OutputTemplate>,
+}
+
+impl ResponseTransform {
+    pub fn apply(&self, response: &str, message: &Message) -> Result<String> {
+        if self.extract.is_none() && self.merge.is_none() && self.template.is_none() {
+            return Ok(response.to_string());
+        }
+        let mut value: serde_json::Value =
+            serde_json::from_str(response).map_err(|e| anyhow!("Response is not JSON. ({})", e))?;
+        let mut body = None;
+
+        if let Some(JsonPath(path)) = &self.extract {
+            value = lookup(&value, path)
+                .cloned()
+                .ok_or_else(|| anyhow!("Response field `{}` is missing.", path.join(".")))?;
+        }
+        if let Some(OutputMerge(fields)) = &self.merge {
+            let object = value
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("Response should be a JSON object to merge fields."))?;
+            for (key, field) in fields {
+                object.insert(key.clone(), field.value(message, &mut body)?);
+            }
+        }
+        if let Some(template) = &self.template {
+            let mut rendered = String::new();
+            for part in &template.parts {
+                match part {
+                    Part::Literal(literal) => rendered.push_str(literal),
+                    Part::Response(path) => rendered.push_str(
+                        &lookup(&value, path)
+                            .ok_or_else(|| {
+                                anyhow!("Response field `{}` is missing.", path.join("."))
+                            })?
+                            .to_string(),
+                    ),
+                    Part::Field(field) => {
+                        rendered.push_str(&field.value(message, &mut body)?.to_string())
+                    }
+                }
+            }
+            return Ok(rendered);
+        }
+
+        // a string is forwarded as is, rather than as a quoted JSON string
+        Ok(match value {
+            serde_json::Value::String(s) => s,
+            value => value.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn message() -> Message {
+        Message {
+            body: r#"{"order_id":42}"#.to_string(),
+            receipt_handle: "receipt_handle".to_string(),
+            md5_of_body: "dummy".to_string(),
+            message_id: "message_id".to_string(),
+            attributes: [("kind".to_string(), "batch".to_string())]
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
+        }
+    }
+
+    fn json(s: &str) -> serde_json::Value {
+        serde_json::from_str(s).unwrap()
+    }
+
+    const RESPONSE: &str = r#"{"result":{"status":"done","items":[1,2]}}"#;
+
+    #[test]
+    fn test_apply() {
+        let message = message();
+        let apply = |transform: ResponseTransform| transform.apply(RESPONSE, &message);
+
+        assert_eq!(
+            apply(ResponseTransform::default()).unwrap(),
+            RESPONSE.to_string()
+        );
+        assert_eq!(
+            apply(ResponseTransform {
+                extract: Some("result.status".parse().unwrap()),
+                ..Default::default()
+            })
+            .unwrap(),
+            "done"
+        );
+        assert_eq!(
+            json(
+                &apply(ResponseTransform {
+                    extract: Some("result".parse().unwrap()),
+                    merge: Some("order_id=body.order_id, kind=attr.kind".parse().unwrap()),
+                    ..Default::default()
+                })
+                .unwrap()
+            ),
+            json(r#"{"status":"done","items":[1,2],"order_id":42,"kind":"batch"}"#)
+        );
+        assert_eq!(
+            json(
+                &apply(ResponseTransform {
+                    template: Some(
+                        r#"{"id": {message_id}, "order": {"id": {body.order_id}}, "items": {response.result.items}}"#
+                            .parse()
+                            .unwrap()
+                    ),
+                    ..Default::default()
+                })
+                .unwrap()
+            ),
+            json(r#"{"id":"message_id","order":{"id":42},"items":[1,2]}"#)
+        );
+
+        assert!(apply(ResponseTransform {
+            extract: Some("result.unknown".parse().unwrap()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(apply(ResponseTransform {
+            extract: Some("result.status".parse().unwrap()),
+            merge: Some("id=message_id".parse().unwrap()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(ResponseTransform {
+            extract: Some("result".parse().unwrap()),
+            ..Default::default()
+        }
+        .apply("not json", &message)
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(JsonPath::from_str("").is_err());
+        assert!(OutputMerge::from_str("order_id").is_err());
+        assert!(OutputMerge::from_str("order_id=unknown").is_err());
+        assert!(OutputTemplate::from_str("{unknown}").is_err());
+        assert!(OutputTemplate::from_str(r#"{"a": 1}"#).is_ok());
+    }
+}
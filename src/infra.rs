@@ -1,4 +1,14 @@
 pub mod api;
 pub mod aws;
+pub mod exec;
+pub mod grpc;
 pub mod logging;
+pub mod metrics;
+pub mod oauth2;
+pub mod signature;
+pub mod sigv4;
 pub mod sqs;
+pub mod telemetry;
+pub mod tls;
+pub mod unix;
+pub mod watched_file;
@@ -1,59 +1,817 @@
+use crate::domain::config::{HeaderSource, RequestFormat};
+use crate::domain::error::ApiError;
+use crate::domain::message::Message;
+use crate::domain::template::RequestTemplate;
 use crate::Config;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 #[cfg(test)]
 use mockall::automock;
-use std::time::Duration;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, StatusCode};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{warn, Span};
 use url::Url;
 
+use crate::infra::exec::ExecApi;
+use crate::infra::grpc::GrpcApi;
+use crate::infra::oauth2::TokenProvider;
+use crate::infra::signature;
+use crate::infra::sigv4::SigV4Signer;
+use crate::infra::telemetry;
+use crate::infra::tls::{TlsContents, TlsFiles};
+use crate::infra::unix::{self, UnixClient};
+use crate::infra::watched_file::WatchedFile;
+
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait Api {
-    async fn get(&self, url: &Url) -> Result<()>;
-    async fn post(&self, data: String, message_id: &str) -> Result<(bool, String)>;
+    async fn get(&self, url: &Url) -> Result<(), ApiError>;
+    async fn post(&self, message: &Message) -> Result<(bool, String), ApiError>;
 }
 
 pub struct ApiImpl {
     pub config: Config,
+    client: Mutex<ClientState>,
+    tls_files: Option<TlsFiles>,
+    /// `api_url`, or its HTTP form if it is a Unix domain socket.
+    api_url: Url,
+    /// Set if `api_url` is a Unix domain socket.
+    unix_client: Option<UnixClient>,
+    headers: Vec<(HeaderName, HeaderValueSource)>,
+    token_provider: Option<TokenProvider>,
+    signing_key: Option<WatchedFile>,
+    sigv4_signer: Option<SigV4Signer>,
+}
+
+/// TLS files are checked for modification at most this often.
+const TLS_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The clients of `ApiImpl`, which are built again when the TLS files are rotated.
+struct ClientState {
+    client: reqwest::Client,
+    /// Set if `--api-oauth2-token-url` is set.
+    token_client: Option<reqwest::Client>,
+    /// Contents of the TLS files which `client` is built with.
+    contents: TlsContents,
+    checked_at: Instant,
+    /// Error of the last check, so that a lasting error is logged once.
+    error: Option<String>,
+}
+
+/// Builds the `Api` implementation selected by the configuration.
+pub fn new_api(config: Config) -> Result<Arc<dyn Api + Send + Sync>> {
+    Ok(if config.api_exec_command.is_some() {
+        Arc::new(ExecApi::new(config)?)
+    } else if config.api_grpc_method.is_some() {
+        Arc::new(GrpcApi::new(config)?)
+    } else {
+        Arc::new(ApiImpl::new(config)?)
+    })
+}
+
+/// Total timeout of a request, which can be overridden per message by the attribute
+/// named `--api-timeout-attribute` up to `--api-max-timeout-msec`.
+pub fn request_timeout(config: &Config, message: &Message) -> Duration {
+    let msec = message
+        .attributes
+        .get(&config.api_timeout_attribute)
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|v| v.min(config.api_max_timeout_msec))
+        .unwrap_or(config.api_timeout_msec);
+    Duration::from_millis(msec)
+}
+
+/// Resolved `HeaderSource`.
+enum HeaderValueSource {
+    Static(HeaderValue),
+    File(WatchedFile),
 }
 
 impl ApiImpl {
-    pub fn new(config: Config) -> Self {
-        ApiImpl { config }
+    pub fn new(config: Config) -> Result<Self> {
+        let tls_files = TlsFiles::new(&config);
+        let tls_contents = match &tls_files {
+            None => TlsContents::default(),
+            Some(files) => files.read()?,
+        };
+        let client = Self::build_client(&config, tls_files.as_ref(), &tls_contents)?;
+        let token_client = Self::build_token_client(&config, tls_files.as_ref(), &tls_contents)?;
+        let api_url = config
+            .api_url
+            .clone()
+            .ok_or_else(|| anyhow!("`--api-url` is not set."))?;
+        let (api_url, unix_client) = match unix::split_url(&api_url) {
+            None => (api_url, None),
+            Some((socket, url)) => (url, Some(UnixClient::new(socket))),
+        };
+        let headers = config
+            .api_header
+            .iter()
+            .map(|header| {
+                let source = match &header.source {
+                    HeaderSource::Literal(value) => {
+                        HeaderValueSource::Static(HeaderValue::from_str(value)?)
+                    }
+                    HeaderSource::Env(variable) => {
+                        let value = std::env::var(variable).map_err(|_| {
+                            anyhow!("Environment variable `{}` is not set.", variable)
+                        })?;
+                        HeaderValueSource::Static(HeaderValue::from_str(&value)?)
+                    }
+                    HeaderSource::File(path) => HeaderValueSource::File(WatchedFile::new(path)),
+                };
+                Ok((header.name.clone(), source))
+            })
+            .collect::<Result<_>>()?;
+        let token_provider = match &config.api_oauth2_token_url {
+            None => None,
+            Some(token_url) => Some(TokenProvider::new(
+                token_url.clone(),
+                config.api_oauth2_client_id.clone().unwrap_or_default(),
+                config.api_oauth2_client_secret.clone().unwrap_or_default(),
+                config.api_oauth2_scope.clone(),
+            )),
+        };
+        let signing_key = config.api_signing_key_file.as_ref().map(WatchedFile::new);
+        let sigv4_signer = config
+            .api_sigv4_service
+            .as_ref()
+            .map(|service| SigV4Signer::new(config.clone(), service.clone()));
+        Ok(ApiImpl {
+            config,
+            client: Mutex::new(ClientState {
+                client,
+                token_client,
+                contents: tls_contents,
+                checked_at: Instant::now(),
+                error: None,
+            }),
+            tls_files,
+            api_url,
+            unix_client,
+            headers,
+            token_provider,
+            signing_key,
+            sigv4_signer,
+        })
+    }
+
+    fn build_client(
+        config: &Config,
+        tls_files: Option<&TlsFiles>,
+        tls_contents: &TlsContents,
+    ) -> Result<reqwest::Client> {
+        let builder = Self::client_builder(config, tls_files, tls_contents)?;
+        let builder = if config.api_http2_prior_knowledge {
+            builder.http2_prior_knowledge()
+        } else {
+            builder
+        };
+        Ok(builder.build()?)
+    }
+
+    /// The client of the OAuth2 token endpoint if it is set, which is not the API and so does
+    /// not use `--api-http2-prior-knowledge`. Each request is bounded by `--api-timeout-msec`,
+    /// since the token cache is locked while a token is requested.
+    fn build_token_client(
+        config: &Config,
+        tls_files: Option<&TlsFiles>,
+        tls_contents: &TlsContents,
+    ) -> Result<Option<reqwest::Client>> {
+        if config.api_oauth2_token_url.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(
+            Self::client_builder(config, tls_files, tls_contents)?
+                .timeout(Duration::from_millis(config.api_timeout_msec))
+                .build()?,
+        ))
+    }
+
+    fn client_builder(
+        config: &Config,
+        tls_files: Option<&TlsFiles>,
+        tls_contents: &TlsContents,
+    ) -> Result<reqwest::ClientBuilder> {
+        let builder = reqwest::Client::builder()
+            .user_agent(format!("sqsdproxy/{}", env!("CARGO_PKG_VERSION")))
+            .connect_timeout(Duration::from_millis(config.api_connect_timeout_msec))
+            .pool_idle_timeout(Duration::from_secs(config.api_pool_idle_timeout_seconds))
+            .tcp_keepalive(config.api_tcp_keepalive_seconds.map(Duration::from_secs));
+        let builder = match config.api_pool_max_idle_per_host {
+            None => builder,
+            Some(max) => builder.pool_max_idle_per_host(max),
+        };
+        Ok(match tls_files {
+            None => builder,
+            Some(files) => files.apply(builder, tls_contents)?,
+        })
+    }
+
+    fn client(&self) -> reqwest::Client {
+        self.state().client.clone()
+    }
+
+    /// The clients, built again if the TLS files are modified, which is checked at most once per
+    /// `TLS_CHECK_INTERVAL`. While the new files are broken (e.g. only the certificate is rotated
+    /// yet), the current clients are kept.
+    fn state(&self) -> MutexGuard<'_, ClientState> {
+        let mut state = self.client.lock().unwrap();
+        if let Some(files) = &self.tls_files {
+            if state.checked_at.elapsed() >= TLS_CHECK_INTERVAL {
+                state.checked_at = Instant::now();
+                let reloaded = files.read().and_then(|contents| {
+                    if state.contents.is_outdated(&contents) {
+                        let client = Self::build_client(&self.config, Some(files), &contents)?;
+                        state.token_client =
+                            Self::build_token_client(&self.config, Some(files), &contents)?;
+                        state.client = client;
+                        state.contents = contents;
+                    }
+                    Ok(())
+                });
+                match reloaded {
+                    Ok(()) => state.error = None,
+                    Err(e) => {
+                        let error = format!("{:?}", e);
+                        if state.error.as_ref() != Some(&error) {
+                            warn!(
+                                "Failed to reload TLS files. Keep the current ones. ({})",
+                                error
+                            );
+                            state.error = Some(error);
+                        }
+                    }
+                }
+            }
+        }
+        state
+    }
+
+    /// Bearer token of `--api-oauth2-token-url`, if set.
+    async fn token(&self) -> Result<Option<String>, ApiError> {
+        match &self.token_provider {
+            None => Ok(None),
+            Some(token_provider) => {
+                let client = self.state().token_client.clone().unwrap();
+                Ok(Some(token_provider.token(&client).await?))
+            }
+        }
+    }
+
+    fn request(
+        &self,
+        client: &reqwest::Client,
+        message: &Message,
+        token: Option<&str>,
+    ) -> Result<reqwest::RequestBuilder, ApiError> {
+        let (method, url) = match &self.config.api_request_template {
+            None => (Method::POST, self.api_url.clone()),
+            Some(template) => (template.method.clone(), self.render_url(template, message)?),
+        };
+        let (body, content_type) = match self.config.request_format {
+            RequestFormat::Raw => (message.body.clone(), self.config.content_type.as_str()),
+            RequestFormat::Envelope => (message.envelope(), "application/json"),
+        };
+        let mut request = client
+            .request(method, url)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .header("X-SQSPROXYD-MESSAGE-ID", &message.message_id)
+            .header("X-ECHO-TIME", "0")
+            .timeout(request_timeout(&self.config, message))
+            .headers(self.custom_headers()?)
+            .body(body.clone());
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(key) = &self.signing_key {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let signature = signature::sign(
+                key.read_string()?.as_bytes(),
+                timestamp,
+                &message.message_id,
+                body.as_bytes(),
+            );
+            request = request
+                .header(signature::TIMESTAMP_HEADER, timestamp)
+                .header(signature::SIGNATURE_HEADER, signature);
+        }
+        if let Some(trace_header) = &message.trace_header {
+            request = request.header("X-Amzn-Trace-Id", trace_header);
+        }
+        for (name, value) in telemetry::inject(&Span::current()) {
+            request = request.header(name, value);
+        }
+        Ok(request)
+    }
+
+    /// `--api-url` followed by the path and query rendered from `template`.
+    /// The query of `--api-url`, if any, comes before the rendered one.
+    fn render_url(&self, template: &RequestTemplate, message: &Message) -> Result<Url, ApiError> {
+        let rendered = template
+            .render(message)
+            .map_err(|e| ApiError::InvalidMessage(format!("{:#}", e)))?;
+        let (path, query) = match rendered.split_once('?') {
+            None => (rendered.as_str(), None),
+            Some((path, query)) => (path, Some(query)),
+        };
+        let mut url = self.api_url.clone();
+        url.set_path(&format!(
+            "{}{}",
+            self.api_url.path().trim_end_matches('/'),
+            path
+        ));
+        let query = [self.api_url.query(), query]
+            .into_iter()
+            .flatten()
+            .filter(|query| !query.is_empty())
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query(
+            Some(&query)
+                .filter(|query| !query.is_empty())
+                .map(String::as_str),
+        );
+        Ok(url)
+    }
+
+    async fn send(
+        &self,
+        message: &Message,
+        token: Option<&str>,
+    ) -> Result<reqwest::Response, ApiError> {
+        let client = self.client();
+        let mut request = self
+            .request(&client, message, token)?
+            .build()
+            .map_err(api_error)?;
+        if let Some(signer) = &self.sigv4_signer {
+            signer.sign(&mut request).await?;
+        }
+        match &self.unix_client {
+            None => client.execute(request).await.map_err(api_error),
+            Some(unix_client) => {
+                unix_client
+                    .execute(
+                        request,
+                        self.config.api_read_timeout_msec.map(Duration::from_millis),
+                    )
+                    .await
+            }
+        }
+    }
+
+    /// Headers of `--api-header`. Values from files reflect the latest content.
+    fn custom_headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, source) in &self.headers {
+            let value = match source {
+                HeaderValueSource::Static(value) => value.clone(),
+                HeaderValueSource::File(file) => HeaderValue::from_str(&file.read_string()?)?,
+            };
+            headers.append(name.clone(), value);
+        }
+        Ok(headers)
     }
 }
 
 #[async_trait]
 impl Api for ApiImpl {
-    async fn get(&self, url: &Url) -> Result<()> {
-        let client = reqwest::Client::new();
-        let _ = client
-            .get(url.clone())
-            .header(
-                reqwest::header::USER_AGENT,
-                format!("sqsdproxy/{}", env!("CARGO_PKG_VERSION")),
-            )
-            .timeout(Duration::from_secs(3600))
-            .send()
-            .await?;
+    async fn get(&self, url: &Url) -> Result<(), ApiError> {
+        match unix::split_url(url) {
+            None => {
+                self.client()
+                    .get(url.clone())
+                    .timeout(Duration::from_secs(3600))
+                    .send()
+                    .await
+                    .map_err(api_error)?;
+            }
+            Some((socket, url)) => {
+                let request = self
+                    .client()
+                    .get(url)
+                    .timeout(Duration::from_secs(3600))
+                    .build()
+                    .map_err(api_error)?;
+                UnixClient::new(socket).execute(request, None).await?;
+            }
+        }
         Ok(())
     }
 
-    async fn post(&self, data: String, message_id: &str) -> Result<(bool, String)> {
-        let client = reqwest::Client::new();
-        let res = client
-            .post(self.config.api_url.clone())
-            .header(
-                reqwest::header::USER_AGENT,
-                format!("sqsdproxy/{}", env!("CARGO_PKG_VERSION")),
-            )
-            .header(reqwest::header::CONTENT_TYPE, &self.config.content_type)
-            .header("X-SQSPROXYD-MESSAGE-ID", message_id)
-            .header("X-ECHO-TIME", "0")
-            .timeout(Duration::from_secs(self.config.api_timeout_msec))
-            .body(data)
-            .send()
-            .await?;
-        Ok((res.status().is_success(), res.text().await?))
+    async fn post(&self, message: &Message) -> Result<(bool, String), ApiError> {
+        let token = self.token().await?;
+        let mut res = self.send(message, token.as_deref()).await?;
+        // The token may be revoked before its expiry, so refresh it and retry once.
+        if let (Some(token_provider), Some(rejected)) = (&self.token_provider, token) {
+            if res.status() == StatusCode::UNAUTHORIZED {
+                token_provider.invalidate(&rejected).await;
+                let token = self.token().await?;
+                res = self.send(message, token.as_deref()).await?;
+            }
+        }
+        let status = res.status();
+        let text = match self.config.api_read_timeout_msec {
+            None => res.text().await.map_err(api_error)?,
+            Some(msec) => tokio::time::timeout(Duration::from_millis(msec), res.text())
+                .await
+                .map_err(|_| ApiError::Timeout("Timed out reading API response body.".to_string()))?
+                .map_err(api_error)?,
+        };
+        let message = format!("{} {}", status, text);
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => Err(ApiError::Throttled(message)),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(ApiError::Auth(message)),
+            status if status.is_server_error() => Err(ApiError::ServerError(message)),
+            status => Ok((status.is_success(), text)),
+        }
+    }
+}
+
+fn api_error(e: reqwest::Error) -> ApiError {
+    if e.is_timeout() {
+        ApiError::Timeout(e.to_string())
+    } else if e.is_connect() {
+        ApiError::Connection(e.to_string())
+    } else {
+        ApiError::Other(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use structopt::StructOpt;
+
+    fn config() -> Config {
+        Config::from_iter(&[
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-url",
+            "http://localhost:4000/",
+        ])
+    }
+
+    fn message(attributes: &[(&str, &str)]) -> Message {
+        Message {
+            body: "{}".to_string(),
+            receipt_handle: "receipt_handle".to_string(),
+            md5_of_body: "dummy".to_string(),
+            message_id: "message_id".to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
+        }
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let mut config = config();
+        config.api_timeout_msec = 30000;
+        config.api_timeout_attribute = "Timeout".to_string();
+        config.api_max_timeout_msec = 60000;
+        let api = ApiImpl::new(config).unwrap();
+
+        assert_eq!(
+            request_timeout(&api.config, &message(&[])),
+            Duration::from_millis(30000)
+        );
+        assert_eq!(
+            request_timeout(&api.config, &message(&[("Timeout", "5000")])),
+            Duration::from_millis(5000)
+        );
+        assert_eq!(
+            request_timeout(&api.config, &message(&[("Timeout", "90000")])),
+            Duration::from_millis(60000)
+        );
+        assert_eq!(
+            request_timeout(&api.config, &message(&[("Timeout", "invalid")])),
+            Duration::from_millis(30000)
+        );
+    }
+
+    #[test]
+    fn test_custom_headers() {
+        let path = std::env::temp_dir().join(format!("sqsproxyd-{}-api-token", std::process::id()));
+        std::fs::write(&path, "Bearer file-token\n").unwrap();
+        std::env::set_var("SQSPROXYD_TEST_TENANT", "env-tenant");
+
+        let mut config = config();
+        config.api_header = vec![
+            format!("Authorization:file:{}", path.display())
+                .parse()
+                .unwrap(),
+            "X-Tenant:env:SQSPROXYD_TEST_TENANT".parse().unwrap(),
+            "X-Static: static".parse().unwrap(),
+        ];
+        let api = ApiImpl::new(config).unwrap();
+
+        let headers = api.custom_headers().unwrap();
+        assert_eq!(headers["authorization"], "Bearer file-token");
+        assert_eq!(headers["x-tenant"], "env-tenant");
+        assert_eq!(headers["x-static"], "static");
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(api.custom_headers().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_oauth2_token_is_refreshed_on_unauthorized() {
+        use crate::infra::oauth2::tests::token_server;
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response, Server};
+        use std::convert::Infallible;
+        use std::net::SocketAddr;
+
+        // accepts the second token only, as if the first one was revoked
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let response = match req.headers().get("authorization") {
+                    Some(value) if value == "Bearer token-2" => Response::new(Body::from("ok")),
+                    _ => Response::builder().status(401).body(Body::empty()).unwrap(),
+                };
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let api_url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        let (token_url, issued) = token_server(3600).await;
+
+        let mut config = config();
+        config.api_url = Some(api_url);
+        config.api_oauth2_token_url = Some(token_url);
+        config.api_oauth2_client_id = Some("client".to_string());
+        config.api_oauth2_client_secret = Some("secret".to_string());
+        config.api_oauth2_scope = Some("read".to_string());
+        let api = ApiImpl::new(config).unwrap();
+
+        let (is_succeeded, text) = api.post(&message(&[])).await.unwrap();
+        assert!(is_succeeded);
+        assert_eq!(text, "ok");
+        let (is_succeeded, _) = api.post(&message(&[])).await.unwrap();
+        assert!(is_succeeded);
+        assert_eq!(issued.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_error_status() {
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response, Server};
+        use std::convert::Infallible;
+        use std::net::SocketAddr;
+
+        // responds with the status of the path
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let status = req.uri().path()[1..].parse::<u16>().unwrap();
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from("body"))
+                        .unwrap(),
+                )
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let post = |status: u16| {
+            let mut config = config();
+            config.api_url = Some(Url::parse(&format!("http://{}/{}", addr, status)).unwrap());
+            async move { ApiImpl::new(config).unwrap().post(&message(&[])).await }
+        };
+
+        assert!(matches!(post(200).await, Ok((true, _))));
+        assert!(matches!(post(400).await, Ok((false, _))));
+        assert!(matches!(post(401).await, Err(ApiError::Auth(_))));
+        assert!(matches!(post(403).await, Err(ApiError::Auth(_))));
+        assert!(matches!(post(429).await, Err(ApiError::Throttled(_))));
+        assert!(matches!(post(503).await, Err(ApiError::ServerError(e)) if e.contains("body")));
+    }
+
+    #[tokio::test]
+    async fn test_oauth2_token_client() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        // answers HTTP/1.1 only, and hangs up on the second connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let token_url =
+            Url::parse(&format!("http://{}/token", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let n = socket.read(&mut buf).await.unwrap();
+            if buf[..n].starts_with(b"POST /token HTTP/1.1") {
+                let body = r#"{"access_token":"token-1","expires_in":0}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            let (_socket, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let mut config = config();
+        config.api_http2_prior_knowledge = true;
+        config.api_timeout_msec = 500;
+        config.api_oauth2_token_url = Some(token_url);
+        let api = ApiImpl::new(config).unwrap();
+
+        assert_eq!(api.token().await.unwrap().as_deref(), Some("token-1"));
+        let started = std::time::Instant::now();
+        assert!(api.token().await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_tls_files_are_checked_at_intervals() {
+        use crate::infra::tls::tests::CA_CERT;
+        use std::fs::File;
+
+        let path =
+            std::env::temp_dir().join(format!("sqsproxyd-{}-api-ca.pem", std::process::id()));
+        let rotate = |content: &str, seconds: u64| {
+            std::fs::write(&path, content).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::now() + Duration::from_secs(seconds))
+                .unwrap();
+        };
+        rotate(CA_CERT, 0);
+        let mut config = config();
+        config.api_tls_ca_cert = Some(path.clone());
+        config.api_oauth2_token_url = Some(Url::parse("http://localhost:4001/token").unwrap());
+        let api = ApiImpl::new(config).unwrap();
+        let expire = || api.client.lock().unwrap().checked_at -= TLS_CHECK_INTERVAL;
+
+        // a broken file is not noticed until the interval passes, and then the error is kept
+        rotate(
+            "-----BEGIN CERTIFICATE-----\nbroken\n-----END CERTIFICATE-----",
+            10,
+        );
+        api.client();
+        assert!(api.client.lock().unwrap().error.is_none());
+        expire();
+        api.client();
+        assert!(api.client.lock().unwrap().error.is_some());
+
+        rotate(CA_CERT, 20);
+        api.client();
+        assert!(api.client.lock().unwrap().error.is_some());
+        expire();
+        api.client();
+        assert!(api.client.lock().unwrap().error.is_none());
+
+        // the token client is built again together with the API client
+        let contents = api.tls_files.as_ref().unwrap().read().unwrap();
+        assert!(!api.client.lock().unwrap().contents.is_outdated(&contents));
+        assert!(api.client.lock().unwrap().token_client.is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_request_template() {
+        let mut config = config();
+        config.api_url = Some(Url::parse("http://localhost:4000/v1/?debug=1").unwrap());
+        config.api_request_template = Some(
+            "PUT /orders/{attr.order_id}?id={message_id}"
+                .parse()
+                .unwrap(),
+        );
+        let api = ApiImpl::new(config).unwrap();
+
+        let request = api
+            .request(&api.client(), &message(&[("order_id", "42")]), None)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.method(), Method::PUT);
+        assert_eq!(
+            request.url().as_str(),
+            "http://localhost:4000/v1/orders/42?debug=1&id=message_id"
+        );
+
+        assert!(matches!(
+            api.request(&api.client(), &message(&[]), None),
+            Err(ApiError::InvalidMessage(_))
+        ));
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut config = config();
+        config.content_type = "text/plain".to_string();
+        config.request_format = RequestFormat::Envelope;
+        let api = ApiImpl::new(config).unwrap();
+
+        let message = message(&[]);
+        let request = api
+            .request(&api.client(), &message, None)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()["content-type"], "application/json");
+        assert_eq!(
+            request.body().unwrap().as_bytes().unwrap(),
+            message.envelope().as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_signature() {
+        let path =
+            std::env::temp_dir().join(format!("sqsproxyd-{}-signing-key", std::process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+
+        let mut config = config();
+        config.api_signing_key_file = Some(path.clone());
+        let api = ApiImpl::new(config).unwrap();
+
+        let message = message(&[]);
+        let request = api
+            .request(&api.client(), &message, None)
+            .unwrap()
+            .build()
+            .unwrap();
+        let header = |name| request.headers()[name].to_str().unwrap();
+        let timestamp = header(signature::TIMESTAMP_HEADER).parse().unwrap();
+        assert!(signature::verify(
+            b"secret",
+            timestamp,
+            &message.message_id,
+            message.body.as_bytes(),
+            header(signature::SIGNATURE_HEADER),
+            timestamp,
+            Duration::from_secs(0)
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use hyper::server::conn::Http;
+        use hyper::service::service_fn;
+        use hyper::{Body, Request, Response};
+        use std::convert::Infallible;
+        use tokio::net::UnixListener;
+
+        let socket =
+            std::env::temp_dir().join(format!("sqsproxyd-{}-api.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(Http::new().serve_connection(
+                    stream,
+                    service_fn(|req: Request<Body>| async move {
+                        let text = format!("{} {}", req.method(), req.uri());
+                        Ok::<_, Infallible>(Response::new(Body::from(text)))
+                    }),
+                ));
+            }
+        });
+
+        let mut config = config();
+        config.api_url =
+            Some(Url::parse(&format!("unix://{}:/jobs?type=batch", socket.display())).unwrap());
+        let api = ApiImpl::new(config).unwrap();
+
+        let (is_succeeded, text) = api.post(&message(&[])).await.unwrap();
+        assert!(is_succeeded);
+        assert_eq!(text, "POST /jobs?type=batch");
+        api.get(&Url::parse(&format!("unix://{}:/health", socket.display())).unwrap())
+            .await
+            .unwrap();
+
+        std::fs::remove_file(&socket).unwrap();
+
+        // a new client, because the pooled connection outlives the socket file
+        let api = ApiImpl::new(api.config.clone()).unwrap();
+        assert!(matches!(
+            api.post(&message(&[])).await,
+            Err(ApiError::Connection(_))
+        ));
     }
 }
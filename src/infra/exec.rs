@@ -0,0 +1,285 @@
+use crate::domain::config::KillPolicy;
+use crate::domain::error::ApiError;
+use crate::domain::message::Message;
+use crate::infra::api::{request_timeout, Api};
+use crate::infra::telemetry;
+use crate::Config;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use std::io;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tracing::{info, warn, Span};
+use url::Url;
+
+/// `Api` which runs `--api-exec-command` for each message instead of calling HTTP.
+///
+/// The body is written to stdin, and the metadata is passed as environment variables.
+/// Exit code 0 means success, and stdout is the response.
+pub struct ExecApi {
+    config: Config,
+    command: String,
+}
+
+impl ExecApi {
+    pub fn new(config: Config) -> Result<Self> {
+        let command = config
+            .api_exec_command
+            .clone()
+            .ok_or_else(|| anyhow!("`--api-exec-command` is not set."))?;
+        Ok(ExecApi { config, command })
+    }
+
+    fn envs(message: &Message) -> Vec<(String, String)> {
+        let mut envs = vec![(
+            "SQSPROXYD_MESSAGE_ID".to_string(),
+            message.message_id.clone(),
+        )];
+        if let Some(receive_count) = message.receive_count {
+            envs.push((
+                "SQSPROXYD_RECEIVE_COUNT".to_string(),
+                receive_count.to_string(),
+            ));
+        }
+        if let Some(sent_timestamp) = message.sent_timestamp {
+            envs.push((
+                "SQSPROXYD_SENT_TIMESTAMP".to_string(),
+                sent_timestamp.to_string(),
+            ));
+        }
+        if let Some(trace_header) = &message.trace_header {
+            envs.push(("SQSPROXYD_TRACE_HEADER".to_string(), trace_header.clone()));
+        }
+        for (name, value) in &message.attributes {
+            envs.push((
+                format!("SQSPROXYD_ATTRIBUTE_{}", env_name(name)),
+                value.clone(),
+            ));
+        }
+        for (name, value) in telemetry::inject(&Span::current()) {
+            envs.push((env_name(&name), value));
+        }
+        envs
+    }
+
+    /// Stops the timed out command and its descendants by `--api-exec-kill-policy`.
+    ///
+    /// With `term`, the rest of the process group is killed as soon as `sh` exits or the grace
+    /// period ends, so that no descendant outlives the message.
+    async fn stop(&self, child: &mut Child, pgid: libc::pid_t) {
+        if self.config.api_exec_kill_policy == KillPolicy::Term {
+            signal_group(pgid, libc::SIGTERM);
+            let grace = Duration::from_millis(self.config.api_exec_kill_grace_msec);
+            let _ = tokio::time::timeout(grace, child.wait()).await;
+        }
+        signal_group(pgid, libc::SIGKILL);
+        if let Err(e) = child.wait().await {
+            warn!("Failed to wait for the killed command. ({})", e);
+        }
+    }
+}
+
+fn signal_group(pgid: libc::pid_t, signal: libc::c_int) {
+    // SAFETY: `kill` has no memory safety requirements.
+    unsafe { libc::kill(-pgid, signal) };
+}
+
+#[async_trait]
+impl Api for ExecApi {
+    /// There is no API to check, so it is always healthy.
+    async fn get(&self, _url: &Url) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    async fn post(&self, message: &Message) -> Result<(bool, String), ApiError> {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .envs(Self::envs(message))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        // in its own process group, so that pipelines and background jobs are stopped together
+        // SAFETY: `setpgid` is async-signal-safe.
+        unsafe {
+            command.pre_exec(|| match libc::setpgid(0, 0) {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            });
+        }
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to run `{}`.", self.command))?;
+        // the id is kept until the child is waited
+        let pgid = child.id().expect("the command is not waited yet") as libc::pid_t;
+
+        // written in background, so that a command which does not read stdin does not block
+        let mut stdin = child.stdin.take().unwrap();
+        let body = message.body.clone();
+        tokio::spawn(async move {
+            let _ = stdin.write_all(body.as_bytes()).await;
+        });
+        let stdout = tokio::spawn(read_to_string(child.stdout.take().unwrap()));
+        let stderr = tokio::spawn(read_to_string(child.stderr.take().unwrap()));
+
+        // stdout and stderr are read within the timeout too, because a background job may keep
+        // them open after `sh` exits
+        let timeout = request_timeout(&self.config, message);
+        let output = tokio::time::timeout(timeout, async {
+            let status = child
+                .wait()
+                .await
+                .context("Failed to wait for the command.")?;
+            let stdout = stdout.await.map_err(|e| anyhow!(e))??;
+            let stderr = stderr.await.map_err(|e| anyhow!(e))??;
+            Ok::<_, anyhow::Error>((status, stdout, stderr))
+        })
+        .await;
+        let (status, stdout, stderr) = match output {
+            Ok(output) => output?,
+            Err(_) => {
+                self.stop(&mut child, pgid).await;
+                return Err(ApiError::Timeout(format!(
+                    "Command did not exit within {:?}.",
+                    timeout
+                )));
+            }
+        };
+
+        let stderr = stderr.trim_end();
+        if status.success() {
+            if !stderr.is_empty() {
+                info!(stderr, "Command succeeded.");
+            }
+        } else {
+            warn!(stderr, "Command failed. ({})", status);
+        }
+        Ok((status.success(), stdout))
+    }
+}
+
+async fn read_to_string(mut reader: impl AsyncRead + Unpin) -> Result<String> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).await?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// `traceparent` -> `TRACEPARENT`, `Content-Type` -> `CONTENT_TYPE`
+fn env_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use structopt::StructOpt;
+
+    fn exec_api(command: &str, kill_policy: KillPolicy) -> ExecApi {
+        let mut config = Config::from_iter(&[
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-exec-command",
+            command,
+        ]);
+        config.api_timeout_msec = 500;
+        config.api_exec_kill_policy = kill_policy;
+        config.api_exec_kill_grace_msec = 500;
+        ExecApi::new(config).unwrap()
+    }
+
+    fn message() -> Message {
+        Message {
+            body: r#"{"id":1}"#.to_string(),
+            receipt_handle: "receipt_handle".to_string(),
+            md5_of_body: "dummy".to_string(),
+            message_id: "message_id".to_string(),
+            attributes: [("order-kind".to_string(), "batch".to_string())]
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+            receive_count: Some(2),
+            sent_timestamp: None,
+            trace_header: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let api = exec_api(
+            r#"echo "$SQSPROXYD_MESSAGE_ID $SQSPROXYD_RECEIVE_COUNT $SQSPROXYD_ATTRIBUTE_ORDER_KIND $(cat)""#,
+            KillPolicy::Term,
+        );
+        assert_eq!(
+            api.post(&message()).await.unwrap(),
+            (true, "message_id 2 batch {\"id\":1}\n".to_string())
+        );
+
+        let api = exec_api("echo partial; echo oops >&2; exit 3", KillPolicy::Term);
+        assert_eq!(
+            api.post(&message()).await.unwrap(),
+            (false, "partial\n".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        // ignores SIGTERM, so it is killed after the grace period
+        let api = exec_api("trap '' TERM; sleep 10", KillPolicy::Term);
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            api.post(&message()).await,
+            Err(ApiError::Timeout(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let api = exec_api("sleep 10", KillPolicy::Kill);
+        assert!(matches!(
+            api.post(&message()).await,
+            Err(ApiError::Timeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_background_job() {
+        // the background job keeps stdout open after `sh` exits
+        let path = std::env::temp_dir().join(format!("sqsproxyd-{}-exec-pid", std::process::id()));
+        let api = exec_api(
+            &format!("sleep 10 & echo $! > {}; echo ok", path.display()),
+            KillPolicy::Term,
+        );
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            api.post(&message()).await,
+            Err(ApiError::Timeout(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let pid = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // killed, so it is gone or a zombie waiting for init soon
+        let is_alive = || {
+            std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+                .is_ok_and(|stat| !stat.contains(") Z "))
+        };
+        for _ in 0..100 {
+            if !is_alive() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!is_alive());
+    }
+}
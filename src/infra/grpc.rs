@@ -0,0 +1,397 @@
+use crate::domain::error::ApiError;
+use crate::domain::message::Message;
+use crate::infra::api::{request_timeout, Api};
+use crate::infra::telemetry;
+use crate::Config;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use http::uri::PathAndQuery;
+use std::collections::HashMap;
+use std::time::Duration;
+use tonic::codec::ProstCodec;
+use tonic::metadata::{MetadataKey, MetadataValue};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+use tracing::Span;
+use url::Url;
+
+const HEALTH_CHECK_METHOD: &str = "/grpc.health.v1.Health/Check";
+
+// Messages of `proto/sqsproxyd.proto`, written by hand so that building needs no protoc.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProcessRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub body: Vec<u8>,
+    #[prost(map = "string, string", tag = "2")]
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ProcessStatus {
+    Unspecified = 0,
+    Success = 1,
+    Failure = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProcessResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub body: Vec<u8>,
+    #[prost(enumeration = "ProcessStatus", tag = "2")]
+    pub status: i32,
+}
+
+// Messages of the standard `grpc.health.v1` protocol.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HealthCheckRequest {
+    #[prost(string, tag = "1")]
+    pub service: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HealthCheckResponse {
+    /// `SERVING` is 1.
+    #[prost(int32, tag = "1")]
+    pub status: i32,
+}
+
+/// `Api` which calls the unary gRPC method `--api-grpc-method` at `--api-url`.
+pub struct GrpcApi {
+    config: Config,
+    channel: Channel,
+    method: PathAndQuery,
+}
+
+impl GrpcApi {
+    /// Connects lazily, so this must be called in a tokio runtime.
+    pub fn new(config: Config) -> Result<Self> {
+        let api_url = config
+            .api_url
+            .as_ref()
+            .ok_or_else(|| anyhow!("`--api-url` is not set."))?;
+        let method = config
+            .api_grpc_method
+            .as_ref()
+            .ok_or_else(|| anyhow!("`--api-grpc-method` is not set."))?
+            .parse()?;
+        let channel = Self::endpoint(&config, api_url)?.connect_lazy();
+        Ok(GrpcApi {
+            config,
+            channel,
+            method,
+        })
+    }
+
+    fn endpoint(config: &Config, url: &Url) -> Result<Endpoint> {
+        let endpoint = Endpoint::from_shared(url.to_string())?
+            .connect_timeout(Duration::from_millis(config.api_connect_timeout_msec))
+            .tcp_keepalive(config.api_tcp_keepalive_seconds.map(Duration::from_secs));
+        Ok(endpoint)
+    }
+
+    fn metadata(message: &Message) -> HashMap<String, String> {
+        let mut metadata = HashMap::new();
+        metadata.insert("message_id".to_string(), message.message_id.clone());
+        if let Some(receive_count) = message.receive_count {
+            metadata.insert("receive_count".to_string(), receive_count.to_string());
+        }
+        if let Some(sent_timestamp) = message.sent_timestamp {
+            metadata.insert("sent_timestamp".to_string(), sent_timestamp.to_string());
+        }
+        if let Some(trace_header) = &message.trace_header {
+            metadata.insert("trace_header".to_string(), trace_header.clone());
+        }
+        for (name, value) in &message.attributes {
+            metadata.insert(format!("attribute.{}", name), value.clone());
+        }
+        metadata
+    }
+
+    async fn unary<M1, M2>(
+        channel: Channel,
+        method: PathAndQuery,
+        message: M1,
+        timeout: Duration,
+    ) -> Result<Result<M2, Status>, ApiError>
+    where
+        M1: prost::Message + Send + Sync + 'static,
+        M2: prost::Message + Default + Send + Sync + 'static,
+    {
+        let mut request = tonic::Request::new(message);
+        request.set_timeout(timeout);
+        for (name, value) in telemetry::inject(&Span::current()) {
+            if let (Ok(name), Ok(value)) = (
+                MetadataKey::from_bytes(name.as_bytes()),
+                MetadataValue::from_str(&value),
+            ) {
+                request.metadata_mut().insert(name, value);
+            }
+        }
+
+        let mut grpc = tonic::client::Grpc::new(channel);
+        let response = tokio::time::timeout(timeout, async {
+            grpc.ready()
+                .await
+                .map_err(|e| ApiError::Connection(e.to_string()))?;
+            Ok::<_, ApiError>(grpc.unary(request, method, ProstCodec::default()).await)
+        })
+        .await
+        .map_err(|_| {
+            ApiError::Timeout(format!("gRPC call did not complete within {:?}.", timeout))
+        })??;
+        match response {
+            Ok(response) => Ok(Ok(response.into_inner())),
+            Err(status) => match status.code() {
+                Code::Unavailable => Err(ApiError::Connection(status.to_string())),
+                Code::DeadlineExceeded => Err(ApiError::Timeout(status.to_string())),
+                Code::ResourceExhausted => Err(ApiError::Throttled(status.to_string())),
+                Code::Unauthenticated | Code::PermissionDenied => {
+                    Err(ApiError::Auth(status.to_string()))
+                }
+                _ => Ok(Err(status)),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl Api for GrpcApi {
+    /// Calls the standard gRPC health check at `url`.
+    async fn get(&self, url: &Url) -> Result<(), ApiError> {
+        let channel = Self::endpoint(&self.config, url)?.connect_lazy();
+        let response: HealthCheckResponse = Self::unary(
+            channel,
+            PathAndQuery::from_static(HEALTH_CHECK_METHOD),
+            HealthCheckRequest::default(),
+            Duration::from_secs(3600),
+        )
+        .await?
+        .map_err(|status| ApiError::Other(status.into()))?;
+        if response.status != 1 {
+            return Err(ApiError::Other(anyhow!(
+                "gRPC health check status is {}.",
+                response.status
+            )));
+        }
+        Ok(())
+    }
+
+    async fn post(&self, message: &Message) -> Result<(bool, String), ApiError> {
+        let request = ProcessRequest {
+            body: message.body.clone().into_bytes(),
+            metadata: Self::metadata(message),
+        };
+        let response = Self::unary::<_, ProcessResponse>(
+            self.channel.clone(),
+            self.method.clone(),
+            request,
+            request_timeout(&self.config, message),
+        )
+        .await?;
+        match response {
+            Ok(response) => Ok((
+                response.status == ProcessStatus::Success as i32,
+                String::from_utf8_lossy(&response.body).into_owned(),
+            )),
+            // the API fails the message, like a non-2** HTTP status
+            Err(status) => Ok((false, status.message().to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use structopt::StructOpt;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::body::BoxBody;
+    use tonic::transport::{Body, NamedService, Server};
+
+    /// Worker which echoes the body and the message id, fails if the body is `fail` or the name
+    /// of a status code, and leaves the status unspecified if the body is `unspecified`.
+    #[derive(Clone)]
+    struct Worker;
+
+    struct Process;
+
+    impl tonic::server::UnaryService<ProcessRequest> for Process {
+        type Response = ProcessResponse;
+        type Future =
+            Pin<Box<dyn Future<Output = Result<tonic::Response<ProcessResponse>, Status>> + Send>>;
+
+        fn call(&mut self, request: tonic::Request<ProcessRequest>) -> Self::Future {
+            Box::pin(async move {
+                let request = request.into_inner();
+                let code = match &request.body[..] {
+                    b"fail" => Some(Code::Internal),
+                    b"resource_exhausted" => Some(Code::ResourceExhausted),
+                    b"unauthenticated" => Some(Code::Unauthenticated),
+                    b"permission_denied" => Some(Code::PermissionDenied),
+                    _ => None,
+                };
+                if let Some(code) = code {
+                    return Err(Status::new(code, "failed"));
+                }
+                if request.body == b"unspecified" {
+                    return Ok(tonic::Response::new(ProcessResponse {
+                        body: b"unspecified".to_vec(),
+                        status: ProcessStatus::Unspecified as i32,
+                    }));
+                }
+                let body = format!(
+                    "{} {}",
+                    String::from_utf8_lossy(&request.body),
+                    request.metadata["message_id"]
+                );
+                Ok(tonic::Response::new(ProcessResponse {
+                    body: body.into_bytes(),
+                    status: ProcessStatus::Success as i32,
+                }))
+            })
+        }
+    }
+
+    impl tower_service::Service<http::Request<Body>> for Worker {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+            Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                Ok(grpc.unary(Process, request).await)
+            })
+        }
+    }
+
+    impl NamedService for Worker {
+        const NAME: &'static str = "sqsproxyd.v1.Worker";
+    }
+
+    /// Standard health service which is always `SERVING`.
+    #[derive(Clone)]
+    struct Health;
+
+    struct Check;
+
+    impl tonic::server::UnaryService<HealthCheckRequest> for Check {
+        type Response = HealthCheckResponse;
+        type Future = Pin<
+            Box<dyn Future<Output = Result<tonic::Response<HealthCheckResponse>, Status>> + Send>,
+        >;
+
+        fn call(&mut self, _: tonic::Request<HealthCheckRequest>) -> Self::Future {
+            Box::pin(async { Ok(tonic::Response::new(HealthCheckResponse { status: 1 })) })
+        }
+    }
+
+    impl tower_service::Service<http::Request<Body>> for Health {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+            Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                Ok(grpc.unary(Check, request).await)
+            })
+        }
+    }
+
+    impl NamedService for Health {
+        const NAME: &'static str = "grpc.health.v1.Health";
+    }
+
+    /// Serves `Worker`, and `Health` if `healthy`, and returns the URL.
+    async fn serve(healthy: bool) -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let incoming = TcpListenerStream::new(listener);
+        let router = Server::builder().add_service(Worker);
+        if healthy {
+            tokio::spawn(router.add_service(Health).serve_with_incoming(incoming));
+        } else {
+            tokio::spawn(router.serve_with_incoming(incoming));
+        }
+        url
+    }
+
+    fn api(api_url: Url) -> GrpcApi {
+        let mut config = Config::from_iter(&[
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-grpc-method",
+            "/sqsproxyd.v1.Worker/Process",
+        ]);
+        config.api_url = Some(api_url);
+        GrpcApi::new(config).unwrap()
+    }
+
+    fn message(body: &str) -> Message {
+        Message {
+            body: body.to_string(),
+            receipt_handle: "receipt_handle".to_string(),
+            md5_of_body: "dummy".to_string(),
+            message_id: "message_id".to_string(),
+            attributes: HashMap::new(),
+            receive_count: None,
+            sent_timestamp: None,
+            trace_header: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let api = api(serve(false).await);
+
+        assert_eq!(
+            api.post(&message("hello")).await.unwrap(),
+            (true, "hello message_id".to_string())
+        );
+        assert_eq!(
+            api.post(&message("fail")).await.unwrap(),
+            (false, "failed".to_string())
+        );
+        // an unspecified status is a failure, not a success
+        assert_eq!(
+            api.post(&message("unspecified")).await.unwrap(),
+            (false, "unspecified".to_string())
+        );
+        assert!(matches!(
+            api.post(&message("resource_exhausted")).await,
+            Err(ApiError::Throttled(_))
+        ));
+        for body in ["unauthenticated", "permission_denied"] {
+            assert!(matches!(
+                api.post(&message(body)).await,
+                Err(ApiError::Auth(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let healthy = serve(true).await;
+        let api = api(healthy.clone());
+        api.get(&healthy).await.unwrap();
+
+        // the health service is not implemented
+        assert!(api.get(&serve(false).await).await.is_err());
+    }
+}
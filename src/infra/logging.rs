@@ -1,19 +1,32 @@
-use anyhow::{Error, Result};
+use anyhow::Result;
 use std::str::FromStr;
-use tracing;
-use tracing::error;
 use tracing_subscriber;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
+use url::Url;
 
-pub fn setup_logger(rust_log: &str) -> Result<()> {
+use crate::domain::config::LogFormat;
+use crate::infra::telemetry;
+
+/// Installs the log formatter, and the span exporter if `otlp_endpoint` is set.
+/// `rust_log` filters logs only, the spans of sqsproxyd always propagate the trace context
+/// and are exported if `otlp_endpoint` is set.
+pub fn setup_logger(
+    rust_log: &str,
+    log_format: LogFormat,
+    otlp_endpoint: Option<&Url>,
+) -> Result<()> {
     let filter = EnvFilter::from_str(rust_log)?;
-    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+    let fmt_layer = match log_format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
+    let otel_layer = telemetry::layer(otlp_endpoint)?
+        .with_filter(Targets::new().with_target(env!("CARGO_PKG_NAME"), LevelFilter::INFO));
+    tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(filter))
+        .with(otel_layer)
+        .try_init()?;
     Ok(())
 }
-
-pub fn panic(message: &str, e: Error) {
-    let message = format!("{} ({})", message, e);
-    error!("{}", message);
-    panic!("{}", message);
-}
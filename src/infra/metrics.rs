@@ -0,0 +1,150 @@
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+static MESSAGES_RECEIVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sqsproxyd_messages_received_total",
+        "Number of messages received from SQS.",
+        &["queue"]
+    )
+    .unwrap()
+});
+static MESSAGES_PROCESSED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sqsproxyd_messages_processed_total",
+        "Number of messages processed successfully and deleted from SQS.",
+        &["queue"]
+    )
+    .unwrap()
+});
+static MESSAGES_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sqsproxyd_messages_failed_total",
+        "Number of messages failed to process.",
+        &["queue"]
+    )
+    .unwrap()
+});
+static MESSAGES_DEAD_LETTERED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sqsproxyd_messages_dead_lettered_total",
+        "Number of failed messages which reached the maxReceiveCount of the redrive policy.",
+        &["queue"]
+    )
+    .unwrap()
+});
+static MD5_MISMATCHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sqsproxyd_md5_mismatches_total",
+        "Number of messages whose MD5 digest of body mismatched.",
+        &["queue"]
+    )
+    .unwrap()
+});
+static OUTPUT_TRANSFORM_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "sqsproxyd_output_transform_failures_total",
+        "Number of API responses which failed to be transformed and were not forwarded.",
+        &["queue"]
+    )
+    .unwrap()
+});
+static API_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sqsproxyd_api_request_duration_seconds",
+        "Latency of API requests.",
+        &["queue"]
+    )
+    .unwrap()
+});
+static MESSAGE_AGE: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sqsproxyd_message_age_seconds",
+        "Time from sending a message to SQS until it is processed.",
+        &["queue"],
+        vec![0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 21600.0, 86400.0]
+    )
+    .unwrap()
+});
+static SQS_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "sqsproxyd_sqs_request_duration_seconds",
+        "Latency of SQS requests.",
+        &["queue", "operation"]
+    )
+    .unwrap()
+});
+static IN_FLIGHT_MESSAGES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sqsproxyd_in_flight_messages",
+        "Number of messages being processed by workers.",
+        &["queue"]
+    )
+    .unwrap()
+});
+static IDLE_WORKERS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sqsproxyd_idle_workers",
+        "Number of workers waiting for a message.",
+        &["queue"]
+    )
+    .unwrap()
+});
+static CONCURRENCY_LIMIT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sqsproxyd_concurrency_limit",
+        "Current concurrency limit of API requests.",
+        &["queue"]
+    )
+    .unwrap()
+});
+
+/// Metrics labelled by an input queue.
+#[derive(Clone)]
+pub struct Metrics {
+    pub messages_received: IntCounter,
+    pub messages_processed: IntCounter,
+    pub messages_failed: IntCounter,
+    pub messages_dead_lettered: IntCounter,
+    pub md5_mismatches: IntCounter,
+    pub output_transform_failures: IntCounter,
+    pub api_request_duration: Histogram,
+    pub message_age: Histogram,
+    pub in_flight_messages: IntGauge,
+    pub idle_workers: IntGauge,
+    pub concurrency_limit: IntGauge,
+}
+
+impl Metrics {
+    pub fn new(queue: &str) -> Self {
+        Metrics {
+            messages_received: MESSAGES_RECEIVED.with_label_values(&[queue]),
+            messages_processed: MESSAGES_PROCESSED.with_label_values(&[queue]),
+            messages_failed: MESSAGES_FAILED.with_label_values(&[queue]),
+            messages_dead_lettered: MESSAGES_DEAD_LETTERED.with_label_values(&[queue]),
+            md5_mismatches: MD5_MISMATCHES.with_label_values(&[queue]),
+            output_transform_failures: OUTPUT_TRANSFORM_FAILURES.with_label_values(&[queue]),
+            api_request_duration: API_REQUEST_DURATION.with_label_values(&[queue]),
+            message_age: MESSAGE_AGE.with_label_values(&[queue]),
+            in_flight_messages: IN_FLIGHT_MESSAGES.with_label_values(&[queue]),
+            idle_workers: IDLE_WORKERS.with_label_values(&[queue]),
+            concurrency_limit: CONCURRENCY_LIMIT.with_label_values(&[queue]),
+        }
+    }
+}
+
+pub fn sqs_request_duration(queue: &str, operation: &str) -> Histogram {
+    SQS_REQUEST_DURATION.with_label_values(&[queue, operation])
+}
+
+/// Encodes all registered metrics in the Prometheus text format.
+pub fn encode() -> Result<(String, Vec<u8>)> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+    Ok((encoder.format_type().to_string(), buffer))
+}
@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use url::Url;
+
+/// Tokens are refreshed this long before they expire.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// Obtains access tokens with the OAuth2 client credentials grant, and caches them.
+pub struct TokenProvider {
+    token_url: Url,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    cache: Mutex<Option<Token>>,
+}
+
+struct Token {
+    access_token: String,
+    /// `None` if the token endpoint does not tell the lifetime.
+    expires_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl TokenProvider {
+    pub fn new(
+        token_url: Url,
+        client_id: String,
+        client_secret: String,
+        scope: Option<String>,
+    ) -> Self {
+        TokenProvider {
+            token_url,
+            client_id,
+            client_secret,
+            scope,
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Returns the cached token, or requests a new one if it is about to expire.
+    /// The cache stays locked during the request so that concurrent callers share one token,
+    /// so `client` should have a timeout.
+    pub async fn token(&self, client: &reqwest::Client) -> Result<String> {
+        let mut cache = self.cache.lock().await;
+        if let Some(token) = &*cache {
+            if token
+                .expires_at
+                .is_none_or(|expires_at| Instant::now() + EXPIRY_MARGIN < expires_at)
+            {
+                return Ok(token.access_token.clone());
+            }
+        }
+
+        let token = self.request(client).await?;
+        let access_token = token.access_token.clone();
+        *cache = Some(token);
+        Ok(access_token)
+    }
+
+    /// Discards `rejected` so that the next `token()` requests a new one.
+    /// The cache is kept if another request has already refreshed it.
+    pub async fn invalidate(&self, rejected: &str) {
+        let mut cache = self.cache.lock().await;
+        if matches!(&*cache, Some(token) if token.access_token == rejected) {
+            *cache = None;
+        }
+    }
+
+    async fn request(&self, client: &reqwest::Client) -> Result<Token> {
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        let requested_at = Instant::now();
+        let res = client
+            .post(self.token_url.clone())
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&form)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(anyhow!(
+                "Token endpoint returns {}. ({})",
+                res.status(),
+                res.text().await.unwrap_or_default()
+            ));
+        }
+        let res: TokenResponse = res.json().await?;
+        Ok(Token {
+            access_token: res.access_token,
+            expires_at: res
+                .expires_in
+                .map(|seconds| requested_at + Duration::from_secs(seconds)),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Token endpoint issuing `token-1`, `token-2`, ... which expire in `expires_in` seconds.
+    /// Returns its URL and the number of issued tokens.
+    pub async fn token_server(expires_in: u64) -> (Url, Arc<AtomicUsize>) {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let counter = issued.clone();
+        let make_service = make_service_fn(move |_| {
+            let counter = counter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let counter = counter.clone();
+                    async move {
+                        let authorized = req.headers().get("authorization")
+                            == Some(&"Basic Y2xpZW50OnNlY3JldA==".parse().unwrap()); // client:secret
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let response = if authorized
+                            && body.as_ref() == b"grant_type=client_credentials&scope=read"
+                        {
+                            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                            Response::new(Body::from(format!(
+                                r#"{{"access_token":"token-{}","token_type":"Bearer","expires_in":{}}}"#,
+                                n, expires_in
+                            )))
+                        } else {
+                            Response::builder().status(401).body(Body::empty()).unwrap()
+                        };
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = Url::parse(&format!("http://{}/token", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        (url, issued)
+    }
+
+    fn provider(url: Url) -> TokenProvider {
+        TokenProvider::new(
+            url,
+            "client".to_string(),
+            "secret".to_string(),
+            Some("read".to_string()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_token_is_cached_until_invalidated() {
+        let (url, issued) = token_server(3600).await;
+        let provider = provider(url);
+        let client = reqwest::Client::new();
+
+        assert_eq!(provider.token(&client).await.unwrap(), "token-1");
+        assert_eq!(provider.token(&client).await.unwrap(), "token-1");
+        assert_eq!(issued.load(Ordering::SeqCst), 1);
+
+        // a stale rejection does not discard the current token
+        provider.invalidate("token-0").await;
+        assert_eq!(provider.token(&client).await.unwrap(), "token-1");
+
+        provider.invalidate("token-1").await;
+        assert_eq!(provider.token(&client).await.unwrap(), "token-2");
+    }
+
+    #[tokio::test]
+    async fn test_token_is_refreshed_before_expiry() {
+        let (url, issued) = token_server(10).await;
+        let provider = provider(url);
+        let client = reqwest::Client::new();
+
+        assert_eq!(provider.token(&client).await.unwrap(), "token-1");
+        assert_eq!(provider.token(&client).await.unwrap(), "token-2");
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_token_error() {
+        let (url, _) = token_server(3600).await;
+        let provider = TokenProvider::new(
+            url,
+            "client".to_string(),
+            "wrong".to_string(),
+            Some("read".to_string()),
+        );
+        let client = reqwest::Client::new();
+
+        assert!(provider.token(&client).await.is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! HMAC-SHA256 signatures of API requests, with which the API can verify that a request
+//! comes from sqsproxyd.
+//!
+//! The signature is `sha256=` followed by the lowercase hex HMAC-SHA256 of
+//! `{timestamp}.{message_id}.{body}` keyed by the shared secret. The timestamp is Unix
+//! seconds. This module has no dependency on the rest of sqsproxyd, so that
+//! `examples/verify_signature.rs` can include it as is.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+
+pub const TIMESTAMP_HEADER: &str = "X-SQSPROXYD-TIMESTAMP";
+pub const SIGNATURE_HEADER: &str = "X-SQSPROXYD-SIGNATURE";
+
+const PREFIX: &str = "sha256=";
+
+fn mac(key: &[u8], timestamp: u64, message_id: &str, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}.", timestamp, message_id).as_bytes());
+    mac.update(body);
+    mac
+}
+
+pub fn sign(key: &[u8], timestamp: u64, message_id: &str, body: &[u8]) -> String {
+    let signature = mac(key, timestamp, message_id, body)
+        .finalize()
+        .into_bytes();
+    format!("{}{}", PREFIX, hex::encode(signature))
+}
+
+/// Checks `signature` in constant time, and that `timestamp` is within `tolerance` of `now`
+/// so that a captured request cannot be replayed later.
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn verify(
+    key: &[u8],
+    timestamp: u64,
+    message_id: &str,
+    body: &[u8],
+    signature: &str,
+    now: u64,
+    tolerance: Duration,
+) -> bool {
+    if now.abs_diff(timestamp) > tolerance.as_secs() {
+        return false;
+    }
+    let signature = match signature.strip_prefix(PREFIX).map(hex::decode) {
+        Some(Ok(signature)) => signature,
+        _ => return false,
+    };
+    mac(key, timestamp, message_id, body)
+        .verify_slice(&signature)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: Duration = Duration::from_secs(300);
+
+    #[test]
+    fn test_sign() {
+        // echo -n '1700000000.message_id.{"a":1}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign(b"secret", 1700000000, "message_id", br#"{"a":1}"#),
+            "sha256=cc34ac6de4aeee6c5d4a3033b8e7888f716b2617476409a856f8428c4e3cd279"
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let signature = sign(b"secret", 1700000000, "message_id", b"body");
+
+        assert!(verify(
+            b"secret",
+            1700000000,
+            "message_id",
+            b"body",
+            &signature,
+            1700000100,
+            TOLERANCE
+        ));
+        // tampered
+        assert!(!verify(
+            b"secret",
+            1700000000,
+            "message_id",
+            b"body!",
+            &signature,
+            1700000100,
+            TOLERANCE
+        ));
+        assert!(!verify(
+            b"other",
+            1700000000,
+            "message_id",
+            b"body",
+            &signature,
+            1700000100,
+            TOLERANCE
+        ));
+        assert!(!verify(
+            b"secret",
+            1700000000,
+            "message_id",
+            b"body",
+            "sha256=zz",
+            1700000100,
+            TOLERANCE
+        ));
+        // replayed
+        assert!(!verify(
+            b"secret",
+            1700000000,
+            "message_id",
+            b"body",
+            &signature,
+            1700001000,
+            TOLERANCE
+        ));
+    }
+}
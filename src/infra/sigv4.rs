@@ -0,0 +1,115 @@
+use crate::infra::aws::load_aws_config;
+use crate::Config;
+use anyhow::{anyhow, Context, Result};
+use aws_config::Config as AwsConfig;
+use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+use aws_sigv4::SigningParams;
+use aws_types::credentials::ProvideCredentials;
+use std::time::SystemTime;
+use tokio::sync::OnceCell;
+
+/// Signs API requests with AWS Signature Version 4, for API Gateway or Lambda function URLs
+/// with IAM auth. Credentials come from the same chain as the SQS client.
+pub struct SigV4Signer {
+    config: Config,
+    service: String,
+    /// Loaded on the first request, because building `ApiImpl` is synchronous.
+    aws_config: OnceCell<AwsConfig>,
+}
+
+impl SigV4Signer {
+    pub fn new(config: Config, service: String) -> Self {
+        SigV4Signer {
+            config,
+            service,
+            aws_config: OnceCell::new(),
+        }
+    }
+
+    /// Adds `Authorization`, `X-Amz-Date` and `X-Amz-Security-Token` (if any) to `request`.
+    /// This should be the last change to the request, because the headers are signed.
+    pub async fn sign(&self, request: &mut reqwest::Request) -> Result<()> {
+        let aws_config = self
+            .aws_config
+            .get_or_init(|| load_aws_config(&self.config))
+            .await;
+        let region = match (&self.config.api_sigv4_region, aws_config.region()) {
+            (Some(region), _) => region.clone(),
+            (None, Some(region)) => region.to_string(),
+            (None, None) => return Err(anyhow!("AWS region to sign API requests is unknown.")),
+        };
+        let credentials = aws_config
+            .credentials_provider()
+            .ok_or_else(|| anyhow!("No AWS credentials provider is configured."))?
+            .provide_credentials()
+            .await
+            .context("Failed to load AWS credentials to sign API requests.")?;
+
+        let mut builder = SigningParams::builder()
+            .access_key(credentials.access_key_id())
+            .secret_key(credentials.secret_access_key())
+            .region(&region)
+            .service_name(&self.service)
+            .time(SystemTime::now())
+            .settings(SigningSettings::default());
+        builder.set_security_token(credentials.session_token());
+        let params = builder.build()?;
+
+        let uri = request.url().as_str().parse()?;
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .unwrap_or_default();
+        let signable = SignableRequest::new(
+            request.method(),
+            &uri,
+            request.headers(),
+            SignableBody::Bytes(body),
+        );
+        let (mut instructions, _) = sign(signable, &params)
+            .map_err(|e| anyhow!("Failed to sign the API request. ({})", e))?
+            .into_parts();
+        if let Some(headers) = instructions.take_headers() {
+            for (name, value) in headers.iter() {
+                request.headers_mut().insert(name, value.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[tokio::test]
+    async fn test_sign() {
+        let mut config = Config::from_iter(&[
+            "sqsproxyd",
+            "--sqs-url",
+            "http://localhost:9324/queue/sqs",
+            "--api-url",
+            "http://localhost:4000/",
+        ]);
+        config.aws_access_key_id = Some("AKIDEXAMPLE".to_string());
+        config.aws_secret_access_key = Some("SECRET".to_string());
+        config.aws_session_token = Some("TOKEN".to_string());
+        config.aws_region = Some("us-west-2".to_string());
+        config.api_sigv4_region = Some("ap-northeast-1".to_string());
+        let signer = SigV4Signer::new(config, "execute-api".to_string());
+
+        let mut request = reqwest::Client::new()
+            .post("https://example.execute-api.ap-northeast-1.amazonaws.com/prod/jobs")
+            .body("{}")
+            .build()
+            .unwrap();
+        signer.sign(&mut request).await.unwrap();
+
+        let authorization = request.headers()["authorization"].to_str().unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(authorization.contains("/ap-northeast-1/execute-api/aws4_request"));
+        assert!(request.headers().contains_key("x-amz-date"));
+        assert_eq!(request.headers()["x-amz-security-token"], "TOKEN");
+    }
+}
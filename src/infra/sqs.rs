@@ -1,10 +1,17 @@
-use anyhow::Result;
 use async_trait::async_trait;
-use aws_sdk_sqs::{Client, Endpoint};
+use aws_http::auth::CredentialsStageError;
+use aws_sdk_sqs::model::{
+    MessageAttributeValue, MessageSystemAttributeNameForSends, MessageSystemAttributeValue,
+    QueueAttributeName,
+};
+use aws_sdk_sqs::{Client, Endpoint, SdkError};
+use aws_smithy_types::retry::{ErrorKind, ProvideErrorKind};
 
-use crate::domain::message::Message;
+use crate::domain::error::SqsError;
+use crate::domain::message::{Message, OutputMessage};
 
 use crate::infra::aws::load_aws_config;
+use crate::infra::metrics::sqs_request_duration;
 use crate::Config;
 #[cfg(test)]
 use mockall::automock;
@@ -12,9 +19,13 @@ use mockall::automock;
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait Sqs {
-    async fn receive_messages(&self) -> Result<Option<Vec<Message>>>;
-    async fn send_message(&self, body: String) -> Result<()>;
-    async fn delete_message(&self, receipt_handle: String) -> Result<()>;
+    async fn receive_messages(&self) -> Result<Option<Vec<Message>>, SqsError>;
+    async fn send_message(&self, message: OutputMessage) -> Result<(), SqsError>;
+    async fn delete_message(&self, receipt_handle: String) -> Result<(), SqsError>;
+    /// Checks that the queue exists and is accessible.
+    async fn check(&self) -> Result<(), SqsError>;
+    /// `maxReceiveCount` of the redrive policy, if the queue has a dead-letter queue.
+    async fn max_receive_count(&self) -> Result<Option<u32>, SqsError>;
 }
 
 pub struct AwsSqs {
@@ -23,31 +34,80 @@ pub struct AwsSqs {
 }
 
 impl AwsSqs {
-    pub async fn new(url: String, config: &Config) -> Self {
-        let client = match &config.aws_endpoint {
-            None => Client::new(&load_aws_config(config).await),
+    /// `client` is cheap to clone, and clones share the connection pool and the credentials cache.
+    pub fn new(client: Client, url: String) -> Self {
+        AwsSqs { client, url }
+    }
+
+    pub async fn build_client(config: &Config) -> Client {
+        let aws_config = load_aws_config(config).await;
+        match &config.aws_endpoint {
+            None => Client::new(&aws_config),
             Some(aws_endpoint) => {
-                let aws_config = load_aws_config(config).await;
                 let sqs_config = aws_sdk_sqs::config::Builder::from(&aws_config)
                     .endpoint_resolver(Endpoint::immutable(aws_endpoint.clone()))
                     .build();
-                aws_sdk_sqs::Client::from_conf(sqs_config)
+                Client::from_conf(sqs_config)
             }
-        };
-        AwsSqs { client, url }
+        }
+    }
+
+    fn sqs_error<E>(&self, e: SdkError<E>) -> SqsError
+    where
+        E: ProvideErrorKind + std::error::Error + Send + Sync + 'static,
+    {
+        match &e {
+            SdkError::ConstructionFailure(source) if source.is::<CredentialsStageError>() => {
+                SqsError::Auth(source.to_string())
+            }
+            SdkError::TimeoutError(source) => SqsError::Timeout(source.to_string()),
+            SdkError::DispatchFailure(source) if source.is_timeout() => {
+                SqsError::Timeout(source.to_string())
+            }
+            SdkError::DispatchFailure(source) if source.is_io() => {
+                SqsError::Connection(source.to_string())
+            }
+            SdkError::ServiceError { err, raw } => match err.code() {
+                Some("AWS.SimpleQueueService.NonExistentQueue") => {
+                    SqsError::QueueNotFound(self.url.clone())
+                }
+                Some(
+                    code @ ("InvalidClientTokenId"
+                    | "SignatureDoesNotMatch"
+                    | "UnrecognizedClientException"
+                    | "MissingAuthenticationToken"
+                    | "ExpiredToken"
+                    | "AccessDenied"),
+                ) => SqsError::Auth(format!("{}: {}", code, err)),
+                Some(code @ ("ThrottlingException" | "RequestThrottled" | "Throttling")) => {
+                    SqsError::Throttled(format!("{}: {}", code, err))
+                }
+                _ if err.retryable_error_kind() == Some(ErrorKind::ThrottlingError)
+                    || raw.http().status().as_u16() == 429 =>
+                {
+                    SqsError::Throttled(err.to_string())
+                }
+                _ => SqsError::Other(e.into()),
+            },
+            _ => SqsError::Other(e.into()),
+        }
     }
 }
 
 #[async_trait]
 impl Sqs for AwsSqs {
-    async fn receive_messages(&self) -> Result<Option<Vec<Message>>> {
+    async fn receive_messages(&self) -> Result<Option<Vec<Message>>, SqsError> {
+        let _timer = sqs_request_duration(&self.url, "receive_message").start_timer();
         match self
             .client
             .receive_message()
             .queue_url(&self.url)
             .max_number_of_messages(1)
+            .attribute_names(QueueAttributeName::All)
+            .message_attribute_names("All")
             .send()
-            .await?
+            .await
+            .map_err(|e| self.sqs_error(e))?
             .messages
         {
             None => Ok(None),
@@ -55,23 +115,155 @@ impl Sqs for AwsSqs {
         }
     }
 
-    async fn send_message(&self, body: String) -> Result<()> {
-        self.client
+    async fn send_message(&self, message: OutputMessage) -> Result<(), SqsError> {
+        let _timer = sqs_request_duration(&self.url, "send_message").start_timer();
+        let mut request = self
+            .client
             .send_message()
             .queue_url(&self.url)
-            .message_body(body)
-            .send()
-            .await?;
+            .message_body(message.body);
+        for (name, value) in message.attributes {
+            request = request.message_attributes(
+                name,
+                MessageAttributeValue::builder()
+                    .data_type("String")
+                    .string_value(value)
+                    .build(),
+            );
+        }
+        if let Some(trace_header) = message.trace_header {
+            request = request.message_system_attributes(
+                MessageSystemAttributeNameForSends::AwsTraceHeader,
+                MessageSystemAttributeValue::builder()
+                    .data_type("String")
+                    .string_value(trace_header)
+                    .build(),
+            );
+        }
+        request.send().await.map_err(|e| self.sqs_error(e))?;
         Ok(())
     }
 
-    async fn delete_message(&self, receipt_handle: String) -> Result<()> {
+    async fn delete_message(&self, receipt_handle: String) -> Result<(), SqsError> {
+        let _timer = sqs_request_duration(&self.url, "delete_message").start_timer();
         self.client
             .delete_message()
             .queue_url(&self.url)
             .receipt_handle(&receipt_handle)
             .send()
-            .await?;
+            .await
+            .map_err(|e| self.sqs_error(e))?;
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<(), SqsError> {
+        let _timer = sqs_request_duration(&self.url, "get_queue_attributes").start_timer();
+        self.client
+            .get_queue_attributes()
+            .queue_url(&self.url)
+            .attribute_names(QueueAttributeName::QueueArn)
+            .send()
+            .await
+            .map_err(|e| self.sqs_error(e))?;
         Ok(())
     }
+
+    async fn max_receive_count(&self) -> Result<Option<u32>, SqsError> {
+        let _timer = sqs_request_duration(&self.url, "get_queue_attributes").start_timer();
+        let attributes = self
+            .client
+            .get_queue_attributes()
+            .queue_url(&self.url)
+            .attribute_names(QueueAttributeName::RedrivePolicy)
+            .send()
+            .await
+            .map_err(|e| self.sqs_error(e))?
+            .attributes
+            .unwrap_or_default();
+        match attributes.get(&QueueAttributeName::RedrivePolicy) {
+            None => Ok(None),
+            Some(policy) => {
+                // `maxReceiveCount` is either a number or a string depending on how the policy was set.
+                let policy: serde_json::Value =
+                    serde_json::from_str(policy).map_err(anyhow::Error::new)?;
+                Ok(match &policy["maxReceiveCount"] {
+                    serde_json::Value::Number(n) => n.as_u64().map(|n| n as u32),
+                    serde_json::Value::String(s) => s.parse().ok(),
+                    _ => None,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_sqs::error::ReceiveMessageError;
+    use aws_smithy_http::body::SdkBody;
+    use aws_smithy_http::operation;
+
+    fn service_error(code: &str, status: u16) -> SdkError<ReceiveMessageError> {
+        SdkError::ServiceError {
+            err: ReceiveMessageError::generic(
+                aws_smithy_types::Error::builder()
+                    .code(code)
+                    .message("message")
+                    .build(),
+            ),
+            raw: operation::Response::new(
+                http::Response::builder()
+                    .status(status)
+                    .body(SdkBody::empty())
+                    .unwrap(),
+            ),
+        }
+    }
+
+    fn kind(e: &SqsError) -> &'static str {
+        match e {
+            SqsError::Throttled(_) => "throttled",
+            SqsError::QueueNotFound(_) => "queue_not_found",
+            SqsError::Auth(_) => "auth",
+            SqsError::Connection(_) => "connection",
+            SqsError::Timeout(_) => "timeout",
+            SqsError::Other(_) => "other",
+        }
+    }
+
+    #[test]
+    fn test_sqs_error() {
+        let sqs = AwsSqs::new(
+            Client::from_conf(aws_sdk_sqs::Config::builder().build()),
+            "http://localhost:9324/queue/sqs".to_string(),
+        );
+
+        let cases = [
+            (
+                "AWS.SimpleQueueService.NonExistentQueue",
+                400,
+                "queue_not_found",
+            ),
+            ("InvalidClientTokenId", 403, "auth"),
+            ("SignatureDoesNotMatch", 403, "auth"),
+            ("UnrecognizedClientException", 400, "auth"),
+            ("MissingAuthenticationToken", 403, "auth"),
+            ("ExpiredToken", 400, "auth"),
+            ("AccessDenied", 403, "auth"),
+            ("ThrottlingException", 400, "throttled"),
+            ("RequestThrottled", 400, "throttled"),
+            ("Throttling", 400, "throttled"),
+            ("SlowDown", 429, "throttled"),
+            ("InternalError", 500, "other"),
+        ];
+        for (code, status, expected) in cases {
+            let e = sqs.sqs_error(service_error(code, status));
+            assert_eq!(kind(&e), expected, "{} ({})", code, status);
+        }
+
+        assert!(matches!(
+            sqs.sqs_error(service_error("AWS.SimpleQueueService.NonExistentQueue", 400)),
+            SqsError::QueueNotFound(url) if url == "http://localhost:9324/queue/sqs"
+        ));
+    }
 }
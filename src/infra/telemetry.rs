@@ -0,0 +1,99 @@
+use anyhow::Result;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+use url::Url;
+
+/// Builds a layer which exports spans to the OTLP (gRPC) collector at `endpoint`.
+/// Without `endpoint`, spans are not exported but still carry the W3C trace context,
+/// so that the trace of the producer is propagated to the API and the output queue.
+pub fn layer<S>(endpoint: Option<&Url>) -> Result<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let config = trace::config().with_resource(Resource::new(vec![KeyValue::new(
+        "service.name",
+        env!("CARGO_PKG_NAME"),
+    )]));
+    let tracer = match endpoint {
+        None => {
+            let provider = trace::TracerProvider::builder().with_config(config).build();
+            let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+            global::set_tracer_provider(provider);
+            tracer
+        }
+        Some(endpoint) => opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint.as_str()),
+            )
+            .with_trace_config(config)
+            .install_batch(opentelemetry::runtime::Tokio)?,
+    };
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Flushes the spans which are not exported yet.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Extracts the W3C trace context (`traceparent` and `tracestate`) from message attributes.
+pub fn extract(attributes: &HashMap<String, String>) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(attributes))
+}
+
+/// Returns the W3C trace context of `span` as header names (or message attribute names) and values.
+pub fn inject(span: &Span) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut fields)
+    });
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TraceContextExt;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_propagation() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = trace::TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        let attributes: HashMap<String, String> = [(
+            "traceparent".to_string(),
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string(),
+        )]
+        .into_iter()
+        .collect();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("message");
+            span.set_parent(extract(&attributes));
+
+            let fields = inject(&span);
+            let traceparent = fields.get("traceparent").unwrap();
+            assert!(traceparent.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+            assert!(!traceparent.contains("b7ad6b7169203331"));
+            assert_eq!(
+                span.context().span().span_context().trace_id().to_string(),
+                "0af7651916cd43dd8448eb211c80319c"
+            );
+        });
+    }
+}
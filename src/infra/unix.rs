@@ -0,0 +1,247 @@
+use crate::domain::error::ApiError;
+use anyhow::anyhow;
+use hyper::client::connect::{Connected, Connection};
+use hyper::{Body, Client, Uri};
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream;
+use tokio::time::{Duration, Instant};
+use url::Url;
+
+/// Splits `unix:///path/to.sock:/route` into the socket path and the HTTP URL of the route.
+/// Returns `None` for other URLs.
+pub fn split_url(url: &Url) -> Option<(PathBuf, Url)> {
+    if url.scheme() != "unix" {
+        return None;
+    }
+    let (socket, route) = match url.path().split_once(":/") {
+        None => (url.path(), ""),
+        Some((socket, route)) => (socket, route),
+    };
+    let mut http_url = Url::parse("http://localhost/").unwrap();
+    http_url.set_path(route);
+    http_url.set_query(url.query());
+    Some((PathBuf::from(socket), http_url))
+}
+
+/// HTTP/1.1 client which connects to a Unix domain socket instead of the host of the URL.
+pub struct UnixClient {
+    client: Client<UnixConnector>,
+}
+
+impl UnixClient {
+    pub fn new(socket: PathBuf) -> Self {
+        UnixClient {
+            client: Client::builder().build(UnixConnector {
+                socket: Arc::new(socket),
+            }),
+        }
+    }
+
+    /// Sends `request`, which is built by reqwest so that it goes through the same headers
+    /// and signing as TCP requests. As with reqwest, its timeout covers the whole response,
+    /// which is read within `read_timeout` after the headers arrive, if set.
+    pub async fn execute(
+        &self,
+        request: reqwest::Request,
+        read_timeout: Option<Duration>,
+    ) -> Result<reqwest::Response, ApiError> {
+        let deadline = request.timeout().map(|timeout| Instant::now() + *timeout);
+        let mut builder = hyper::Request::builder()
+            .method(request.method().clone())
+            .uri(request.url().as_str());
+        for (name, value) in request.headers() {
+            builder = builder.header(name, value);
+        }
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(|body| Body::from(body.to_vec()))
+            .unwrap_or_else(Body::empty);
+        let request = builder.body(body).map_err(|e| ApiError::Other(e.into()))?;
+
+        let response = self.client.request(request);
+        let response = match deadline {
+            None => response.await,
+            Some(deadline) => tokio::time::timeout_at(deadline, response)
+                .await
+                .map_err(|_| {
+                    ApiError::Timeout("Timed out waiting for the API response.".to_string())
+                })?,
+        }
+        .map_err(hyper_error)?;
+
+        let (parts, body) = response.into_parts();
+        let read_deadline = [
+            deadline,
+            read_timeout.map(|timeout| Instant::now() + timeout),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let body = hyper::body::to_bytes(body);
+        let body = match read_deadline {
+            None => body.await,
+            Some(deadline) => tokio::time::timeout_at(deadline, body).await.map_err(|_| {
+                ApiError::Timeout("Timed out reading API response body.".to_string())
+            })?,
+        }
+        .map_err(hyper_error)?;
+        Ok(hyper::Response::from_parts(parts, Body::from(body)).into())
+    }
+}
+
+fn hyper_error(e: hyper::Error) -> ApiError {
+    if e.is_connect() {
+        ApiError::Connection(e.to_string())
+    } else {
+        ApiError::Other(anyhow!(e))
+    }
+}
+
+#[derive(Clone)]
+struct UnixConnector {
+    socket: Arc<PathBuf>,
+}
+
+impl tower_service::Service<Uri> for UnixConnector {
+    type Response = UnixConnection;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<UnixConnection>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let socket = self.socket.clone();
+        Box::pin(async move { Ok(UnixConnection(connect(&socket).await?)) })
+    }
+}
+
+async fn connect(socket: &Path) -> io::Result<UnixStream> {
+    UnixStream::connect(socket).await.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to connect to `{}`. ({})", socket.display(), e),
+        )
+    })
+}
+
+/// `UnixStream` which hyper can use as a connection.
+struct UnixConnection(UnixStream);
+
+impl Connection for UnixConnection {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for UnixConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_url() {
+        let split = |url| split_url(&Url::parse(url).unwrap());
+
+        assert_eq!(
+            split("unix:///var/run/api.sock:/jobs?type=batch"),
+            Some((
+                PathBuf::from("/var/run/api.sock"),
+                Url::parse("http://localhost/jobs?type=batch").unwrap()
+            ))
+        );
+        assert_eq!(
+            split("unix:///var/run/api.sock"),
+            Some((
+                PathBuf::from("/var/run/api.sock"),
+                Url::parse("http://localhost/").unwrap()
+            ))
+        );
+        assert_eq!(split("http://localhost:4000/"), None);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_covers_body() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::UnixListener;
+
+        // sends the headers and a part of the body, then stalls
+        let socket =
+            std::env::temp_dir().join(format!("sqsproxyd-{}-stall.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nabc")
+                    .await
+                    .unwrap();
+                streams.push(stream);
+            }
+        });
+        let client = UnixClient::new(socket.clone());
+        let request = |timeout| {
+            reqwest::Client::new()
+                .get("http://localhost/")
+                .timeout(timeout)
+                .build()
+                .unwrap()
+        };
+
+        let started = Instant::now();
+        let result = client
+            .execute(request(Duration::from_millis(300)), None)
+            .await;
+        assert!(matches!(result, Err(ApiError::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let started = Instant::now();
+        let result = client
+            .execute(
+                request(Duration::from_secs(60)),
+                Some(Duration::from_millis(300)),
+            )
+            .await;
+        assert!(matches!(result, Err(ApiError::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        std::fs::remove_file(&socket).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// File whose content is cached, and read again only when its modification time changes.
+/// Suitable for mounted secrets which are rotated while the process is running.
+pub struct WatchedFile {
+    path: PathBuf,
+    cache: Mutex<Option<(SystemTime, Arc<Vec<u8>>)>>,
+}
+
+impl WatchedFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        WatchedFile {
+            path: path.into(),
+            cache: Mutex::new(None),
+        }
+    }
+
+    pub fn read(&self) -> Result<Arc<Vec<u8>>> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to stat `{}`.", self.path.display()))?;
+
+        let mut cache = self.cache.lock().unwrap();
+        match &*cache {
+            Some((cached, content)) if *cached == modified => Ok(content.clone()),
+            _ => {
+                let content = Arc::new(
+                    fs::read(&self.path)
+                        .with_context(|| format!("Failed to read `{}`.", self.path.display()))?,
+                );
+                *cache = Some((modified, content.clone()));
+                Ok(content)
+            }
+        }
+    }
+
+    /// Content as a string without the trailing newline.
+    pub fn read_string(&self) -> Result<String> {
+        let content = self.read()?;
+        Ok(String::from_utf8(content.to_vec())?
+            .trim_end_matches(&['\r', '\n'][..])
+            .to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::Duration;
+
+    #[test]
+    fn test_read_again_when_modified() {
+        let path = std::env::temp_dir().join(format!("sqsproxyd-{}-watched", std::process::id()));
+        fs::write(&path, "first\n").unwrap();
+        let file = WatchedFile::new(&path);
+        assert_eq!(file.read_string().unwrap(), "first");
+
+        // not read again while the modification time is unchanged
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, "second\n").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_eq!(file.read_string().unwrap(), "first");
+
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(file.read_string().unwrap(), "second");
+
+        fs::remove_file(&path).unwrap();
+        assert!(file.read().is_err());
+    }
+}
@@ -0,0 +1,6 @@
+pub mod app;
+pub mod domain;
+pub mod infra;
+
+pub use domain::config::Config;
+pub use infra::sqs::AwsSqs;
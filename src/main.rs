@@ -1,49 +1,114 @@
-mod app;
-mod domain;
-mod infra;
-
 use anyhow::{Error, Result};
+use std::process;
+use structopt::clap;
 use tokio::{
     signal::unix::{signal, SignalKind},
     sync::{broadcast, mpsc},
 };
-use tracing::info;
+use tracing::{error, info};
 
-use crate::infra::logging::panic;
-use app::daemon::Daemon;
-use domain::config::Config;
-use infra::{api::ApiImpl, logging::setup_logger, sqs::AwsSqs};
+use sqsproxyd::app::{self, daemon::Daemon, state::State};
+use sqsproxyd::domain::error::FatalError;
+use sqsproxyd::infra::{logging::setup_logger, telemetry};
+use sqsproxyd::Config;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    // get configuration parameters
-    let config = Config::new();
-
-    setup_logger(&config.rust_log)?;
+async fn main() {
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
+        }
+    };
 
-    if let Err(e) = config.validate() {
-        panic("Failed to parse configuration.", e);
+    if let Err(e) = setup_logger(
+        &config.rust_log,
+        config.log_format,
+        config.otlp_endpoint.as_ref(),
+    ) {
+        let e = FatalError::Config(e);
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
     }
 
-    // run daemon
+    let code = match run(config).await {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{}", e);
+            e.exit_code()
+        }
+    };
+    telemetry::shutdown();
+    process::exit(code);
+}
+
+fn load_config() -> Result<Config, FatalError> {
+    let config = Config::try_new().map_err(|e| match e.downcast::<clap::Error>() {
+        Ok(e)
+            if matches!(
+                e.kind,
+                clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed
+            ) =>
+        {
+            e.exit()
+        }
+        Ok(e) => FatalError::Config(e.into()),
+        Err(e) => FatalError::Config(e),
+    })?;
+    config.validate().map_err(FatalError::Config)?;
+    Ok(config)
+}
+
+async fn run(config: Config) -> Result<(), FatalError> {
     let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+    let (reload_tx, reload_rx) = mpsc::channel(1);
     let (heartbeat_tx, mut heartbeat_rx) = mpsc::channel(1);
 
-    let config = config.clone();
+    let daemon = Daemon::new(config.clone()).await?;
+    let state = daemon.state();
+
+    if let Some(addr) = config.admin_addr {
+        let health = daemon.health();
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = app::admin::serve(addr, health, state).await {
+                error!("Admin server stopped. ({:?})", e);
+            }
+        });
+    }
+
+    let mut daemon_task =
+        tokio::spawn(async move { daemon.run(shutdown_rx, reload_rx, heartbeat_tx).await });
 
-    let daemon = Daemon::new(config).await;
-    tokio::spawn(async move { daemon.run(shutdown_rx, heartbeat_tx).await });
+    // configuration reload
+    tokio::spawn(async move {
+        if let Err(e) = receive_reload_signal(reload_tx).await {
+            error!("Failed to receive reload signal. ({:?})", e);
+        }
+    });
 
-    // graceful shutdown
-    if let Err(e) = receive_shutdown_signal().await {
-        panic("Failed to receive shutdown signal.", e);
+    // pause and resume
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = receive_pause_signals(&state).await {
+                error!("Failed to receive pause/resume signals. ({:?})", e);
+            }
+        });
+    }
+
+    // graceful shutdown, unless the daemon stops by itself on a fatal error
+    tokio::select! {
+        result = receive_shutdown_signal() => result?,
+        result = &mut daemon_task => return result.map_err(Error::new)?,
     }
     info!("Start to shutdown.");
+    state.drain();
 
-    if let Err(e) = shutdown_tx.send(()) {
-        panic("Failed to send shutdown message.", Error::new(e));
-    };
+    shutdown_tx.send(()).map_err(Error::new)?;
     let _ = heartbeat_rx.recv().await;
+    daemon_task.await.map_err(Error::new)??;
     info!("Terminated.");
 
     Ok(())
@@ -58,3 +123,34 @@ async fn receive_shutdown_signal() -> Result<()> {
     }
     Ok(())
 }
+
+async fn receive_pause_signals(state: &State) -> Result<()> {
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
+    loop {
+        tokio::select! {
+            _ = sigusr1.recv() => {
+                info!("Receives SIGUSR1 signal.");
+                state.pause();
+            },
+            _ = sigusr2.recv() => {
+                info!("Receives SIGUSR2 signal.");
+                state.resume();
+            }
+        }
+    }
+}
+
+/// Parses the configuration again on `SIGHUP`. The arguments and environment variables are
+/// those of the process, so only the changes of the configuration file take effect.
+async fn receive_reload_signal(reload_tx: mpsc::Sender<Config>) -> Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    loop {
+        sighup.recv().await;
+        info!("Receives SIGHUP signal.");
+        match Config::try_new().and_then(|config| config.validate().map(|_| config)) {
+            Ok(config) => reload_tx.send(config).await?,
+            Err(e) => error!("Failed to reload configuration. ({})", e),
+        }
+    }
+}